@@ -1,9 +1,9 @@
-use glao_error_budget::{ASM, ASMS, OPD};
+use glao_error_budget::{combine_segment_wfe, ASM, ASMS, N_SEGMENT, OPD};
 use std::{iter::Once, time::Instant};
 
 fn main() -> anyhow::Result<()> {
     // Loading the OPD
-    let mut opd =
+    let mut opd: OPD =
         OPD::from_npz("/fsx/CASES/zen30az000_CD12/optvol/optvol_optvol_6.000000e+02.npz")?;
     println!("{}/{}", opd.no_nan_opd().count(), 512 * 512);
     println!("OPD mean: {:.0}nm", 1e9 * opd.mean());
@@ -12,7 +12,8 @@ fn main() -> anyhow::Result<()> {
     // Collecting the 7 modal basis
     println!("Assembling the ASM segments ...");
     let now = Instant::now();
-    let mut asms: Vec<ASM> = ASMS::from_bins()?;
+    let mut asms: Vec<ASM> =
+        ASMS::from_bins_with_progress(|sid| println!(" loaded segment {sid}/{N_SEGMENT}"))?;
     println!(" done in {}s", now.elapsed().as_secs());
 
     opd.mask_with(&asms.mask()).zero_mean();
@@ -40,40 +41,18 @@ fn main() -> anyhow::Result<()> {
         "Segment b squared sum: {:.0?}nm",
         vars.iter().map(|x| 1e9 * x.sqrt()).collect::<Vec<_>>()
     );
-    let c: Vec<_> = {
-        let n_points: Vec<_> = asms.iter().map(|asm| asm.n_point()).collect();
-        let nn_points: usize = n_points.iter().sum();
-        n_points
-            .into_iter()
-            .map(|x| x as f64 / nn_points as f64)
-            .collect()
-    };
-    println!(
-        "OPD std: {:.0}nm",
-        rsss.iter()
-            .zip(&c)
-            .map(|(r, c)| r * r * c)
-            .sum::<f64>()
-            .sqrt()
-    );
+    let c = asms.area_ratios();
+    println!("OPD std: {:.0}nm", combine_segment_wfe(&rsss, &c));
 
-    println!(
-        "Segment Residual WFE RMS: {:.0?}nm",
-        vars.iter()
-            .zip(&rsss)
-            .map(|(x, y)| (y * y - x * 1e18).abs().sqrt())
-            .collect::<Vec<_>>()
-    );
+    let residual_rsss: Vec<_> = vars
+        .iter()
+        .zip(&rsss)
+        .map(|(x, y)| (y * y - x * 1e18).abs().sqrt())
+        .collect();
+    println!("Segment Residual WFE RMS: {:.0?}nm", residual_rsss);
     println!(
         "Residual WFE RMS: {:.0?}nm",
-        (vars
-            .iter()
-            .zip(&rsss)
-            .map(|(x, y)| (y * y - x * 1e18).abs())
-            .zip(&c)
-            .map(|(x, c)| x * c)
-            .sum::<f64>())
-        .sqrt()
+        combine_segment_wfe(&residual_rsss, &c)
     );
 
     let idx = Option::<Once<usize>>::None;
@@ -94,22 +73,12 @@ fn main() -> anyhow::Result<()> {
         (stds.iter().map(|x| x * x).sum::<f64>() / 7f64).sqrt()
     );
 
-    let size = (512, 512);
-    let _: complot::Heatmap = (
-        (opd.map_10e(-6).as_slice(), size),
-        complot::complot!("domeseeing-micron.png"),
-    )
-        .into();
-    let _: complot::Heatmap = (
-        (asm_shape.map_10e(-6).as_slice(), size),
-        complot::complot!("asmshape-micron.png"),
-    )
-        .into();
-    let _: complot::Heatmap = (
-        (residuals.map_10e(-9).as_slice(), size),
-        complot::complot!("residuals-nm.png"),
-    )
-        .into();
+    OPD::new(opd.map_10e(-6)).write_heatmap_png("domeseeing-micron.png")?;
+    OPD::new(asm_shape.map_10e(-6)).write_heatmap_png("asmshape-micron.png")?;
+    OPD::new(residuals.map_10e(-9)).write_heatmap_png("residuals-nm.png")?;
+    asms[0]
+        .mode_as_opd(3)?
+        .write_heatmap_png("M2S1-mode3.png")?;
 
     Ok(())
 }
@@ -0,0 +1,36 @@
+//! Times the projection of a segment-sized OPD on 500 modes
+//!
+//! Run with and without the `simd` feature to compare the scalar and SIMD dot products:
+//! `cargo run --release --example projection [--features simd]`
+//!
+//! The segment is built with its pseudo-inverse, which takes a few seconds before the timing starts
+use glao_error_budget::asm::Segment;
+use std::time::Instant;
+
+fn main() -> anyhow::Result<()> {
+    let (n_point, n_mode) = (21382, 500);
+    let modes: Vec<_> = (0..n_point * n_mode)
+        .map(|k| ((k / n_point + 1) as f64 * (k % n_point) as f64 * 1e-4).sin())
+        .collect();
+    let mask: Vec<_> = (0..512 * 512).map(|k| k < n_point).collect();
+    let segment = Segment::new(n_mode, modes, mask);
+    let opd: Vec<_> = (0..n_point)
+        .map(|k| (k as f64 * 1e-2).cos() * 1e-6)
+        .collect();
+
+    let n_sample = 20;
+    let now = Instant::now();
+    for _ in 0..n_sample {
+        segment.project_out(&opd)?;
+    }
+    println!(
+        "Projection ({}) in {}ms",
+        if cfg!(feature = "simd") {
+            "SIMD"
+        } else {
+            "scalar"
+        },
+        now.elapsed().as_millis() / n_sample
+    );
+    Ok(())
+}
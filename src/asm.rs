@@ -1,7 +1,132 @@
-use crate::{GlaoError, Result};
+use crate::{opd::bilinear_weights, GlaoError, Result, OPD};
 use nalgebra as na;
+use once_cell::sync::{Lazy, OnceCell};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+#[cfg(feature = "simd")]
+use wide::f64x4;
+
+/// Dot product of `a` and `b`, the scalar reference
+fn dot_scalar<T: na::RealField + Copy>(a: &[T], b: &[T]) -> T {
+    a.iter().zip(b).fold(T::zero(), |a, (&x, &y)| a + x * y)
+}
+/// Dot product of `a` and `b`
+fn dot<T: Float>(a: &[T], b: &[T]) -> T {
+    T::dot(a, b)
+}
+/// Pseudo-inverse of the `modes` matrix
+///
+/// Singular values smaller or equal to `tol` are discarded
+fn pseudo_inverse<T: Float>(modes: na::DMatrix<T>, tol: T) -> Result<na::DMatrix<T>> {
+    T::pseudo_inverse(modes, tol)
+}
+/// Returns the threshold of the singular values of a `rows x cols` matrix which largest singular value is `s_max`
+///
+/// The threshold is `tol` but no less than the rounding noise `ε max(rows,cols) s_max`
+/// of the singular values, so that linearly dependent modes are discarded even with `tol=0`
+fn svd_threshold<T: na::RealField + Copy>(tol: T, (rows, cols): (usize, usize), s_max: T) -> T {
+    tol.max(T::default_epsilon() * na::convert(rows.max(cols) as f64) * s_max)
+}
+
+/// Floating point types of the [Segment] modes
+///
+/// The double precision dot product is vectorized with the `simd` feature
+/// and the double precision pseudo-inverse is computed with LAPACK with the `lapack` feature
+pub trait Float: na::RealField + Copy {
+    /// Dot product of `a` and `b`
+    fn dot(a: &[Self], b: &[Self]) -> Self {
+        dot_scalar(a, b)
+    }
+    /// Pseudo-inverse of the `modes` matrix
+    ///
+    /// Singular values smaller or equal to [svd_threshold] are discarded
+    fn pseudo_inverse(modes: na::DMatrix<Self>, tol: Self) -> Result<na::DMatrix<Self>> {
+        let shape = modes.shape();
+        let svd = modes.svd(true, true);
+        let tol = svd_threshold(tol, shape, svd.singular_values.max());
+        svd.pseudo_inverse(tol)
+            .map_err(|e| GlaoError::PseudoInverse(e.into()))
+    }
+}
+impl Float for f32 {}
+impl Float for f64 {
+    /// Dot product of `a` and `b` vectorized in chunks of 4 lanes
+    #[cfg(feature = "simd")]
+    fn dot(a: &[f64], b: &[f64]) -> f64 {
+        let n = a.len().min(b.len());
+        let (a, b) = (&a[..n], &b[..n]);
+        let (a_chunks, b_chunks) = (a.chunks_exact(4), b.chunks_exact(4));
+        let (a_tail, b_tail) = (a_chunks.remainder(), b_chunks.remainder());
+        let acc = a_chunks.zip(b_chunks).fold(f64x4::ZERO, |acc, (x, y)| {
+            f64x4::new([x[0], x[1], x[2], x[3]]).mul_add(f64x4::new([y[0], y[1], y[2], y[3]]), acc)
+        });
+        acc.reduce_add() + dot_scalar(a_tail, b_tail)
+    }
+    /// Pseudo-inverse of the `modes` matrix with a LAPACK QR factorization and SVD
+    ///
+    /// The tall `modes` matrix is factorized as `M = QR` with a thin `Q`,
+    /// and the SVD is computed on the `n_mode x n_mode` matrix `R = U S Vᵀ`
+    /// to avoid the full left singular vectors of `modes`,
+    /// the pseudo-inverse is then `V S⁻¹ UᵀQᵀ`.
+    /// Singular values of `modes` smaller or equal to [svd_threshold] are discarded
+    #[cfg(feature = "lapack")]
+    fn pseudo_inverse(modes: na::DMatrix<f64>, tol: f64) -> Result<na::DMatrix<f64>> {
+        let shape = modes.shape();
+        let qr = nalgebra_lapack::QR::new(modes);
+        let svd = nalgebra_lapack::SVD::new(qr.r())
+            .ok_or_else(|| GlaoError::PseudoInverse("LAPACK SVD failure".into()))?;
+        let tol = svd_threshold(tol, shape, svd.singular_values.max());
+        let s_inv = svd
+            .singular_values
+            .map(|s| if s > tol { s.recip() } else { 0f64 });
+        Ok(svd.vt.transpose()
+            * na::DMatrix::from_diagonal(&s_inv)
+            * svd.u.transpose()
+            * qr.q().transpose())
+    }
+}
+/// Tikhonov regularized pseudo-inverse `(MᵀM + λI)⁻¹Mᵀ` of the `modes` matrix
+fn regularized_pseudo_inverse(modes: &na::DMatrix<f64>, lambda: f64) -> Result<na::DMatrix<f64>> {
+    let n_mode = modes.ncols();
+    let normal = modes.tr_mul(modes) + na::DMatrix::<f64>::identity(n_mode, n_mode) * lambda;
+    normal
+        .cholesky()
+        .map(|chol| chol.solve(&modes.transpose()))
+        .ok_or_else(|| GlaoError::PseudoInverse("singular regularized normal matrix".into()))
+}
+
+/// Modes pseudo-inverse saved by [ASM::from_bin_cached]
+#[derive(Serialize, Deserialize)]
+struct PinvCache {
+    /// Number of modes
+    n_mode: usize,
+    /// Length of the modes vector
+    modes_len: usize,
+    /// FNV-1a hash of the modes, see [modes_hash]
+    modes_hash: u64,
+    /// Modes pseudo-inverse
+    modes_pinv: na::DMatrix<f64>,
+}
+/// Returns the 64 bits FNV-1a hash of the bit patterns of the `modes`
+fn modes_hash(modes: &[f64]) -> u64 {
+    modes
+        .iter()
+        .flat_map(|m| m.to_bits().to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// A lazily loaded shared Karhunen-Loeve modal basis
+type KlCell = Arc<OnceCell<Arc<KarhunenLoeve>>>;
+/// Process-wide cache of the Karhunen-Loeve modal basis, keyed by segment id
+static KL_CACHE: Lazy<Mutex<HashMap<usize, KlCell>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 /// Karhunen-Loeve modal basis
 #[derive(Serialize, Deserialize, Debug)]
@@ -17,7 +142,7 @@ impl KarhunenLoeve {
     /// Loads segment Karhunen-Loeve modes
     ///
     /// The modes are loaded from [bincode] data files in the `gerpy` directory.
-    /// 500 modes are expected.
+    /// Any number of modes is accepted, see [KarhunenLoeve::from_bin_with] to enforce it.
     /// The data files are generated with the `gerpy/export.py` script from `segKLmat.npz`.
     /// The python data transfer interface is created with the binary `gerpy`.
     pub fn from_bin(sid: usize) -> Result<Self> {
@@ -25,76 +150,136 @@ impl KarhunenLoeve {
         let filename = path.join(format!("M2S{sid}")).with_extension("bin");
         println!("Loading {filename:?}");
         let file = File::open(filename)?;
-        Ok(bincode::deserialize_from(file)?)
+        let kl: Self = bincode::deserialize_from(file)?;
+        kl.validate()?;
+        Ok(kl)
+    }
+    /// Loads segment Karhunen-Loeve modes, checking that the data file holds `n_mode` modes
+    pub fn from_bin_with(sid: usize, n_mode: usize) -> Result<Self> {
+        let kl = Self::from_bin(sid)?;
+        if kl.n_mode == n_mode {
+            Ok(kl)
+        } else {
+            Err(GlaoError::NMode(n_mode, kl.n_mode))
+        }
+    }
+    /// Checks that the modes are consistent with the mask
+    ///
+    /// The modes must split evenly into `n_mode` non-empty modes
+    /// with as many points as there are in the pupil mask
+    pub fn validate(&self) -> Result<()> {
+        let n_mask = self.mask.iter().filter(|&&m| m).count();
+        let n_point = self.modes.len().checked_div(self.n_mode).unwrap_or(0);
+        if n_point * self.n_mode != self.modes.len() || n_point == 0 || n_point != n_mask {
+            Err(GlaoError::ModeCount(self.modes.len(), self.n_mode, n_mask))
+        } else {
+            Ok(())
+        }
+    }
+    /// Loads segment Karhunen-Loeve modes once per process
+    ///
+    /// The first call for a given segment id loads the modes with [KarhunenLoeve::from_bin],
+    /// subsequent calls return the same shared data.
+    /// The cached modes are never released: the 7 segments hold about 600MB for the
+    /// lifetime of the process
+    pub fn cached(sid: usize) -> Result<Arc<Self>> {
+        Self::cached_or_load(sid, || Self::from_bin(sid))
+    }
+    fn cached_or_load<F>(sid: usize, load: F) -> Result<Arc<Self>>
+    where
+        F: FnOnce() -> Result<Self>,
+    {
+        // the lock is only held to fetch the segment cell so segments load concurrently
+        let cell = KL_CACHE
+            .lock()
+            .expect("poisoned modes cache")
+            .entry(sid)
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| load().map(Arc::new)).cloned()
+    }
+    /// Loads segment Karhunen-Loeve modes asynchronously
+    ///
+    /// The data file is read with [tokio::fs] and decoded on the blocking thread pool
+    #[cfg(feature = "async")]
+    pub async fn from_bin_async(sid: usize) -> Result<Self> {
+        let path = Path::new("gerpy");
+        let filename = path.join(format!("M2S{sid}")).with_extension("bin");
+        println!("Loading {filename:?}");
+        let bytes = tokio::fs::read(filename).await?;
+        let kl: Self = tokio::task::spawn_blocking(move || bincode::deserialize(&bytes)).await??;
+        kl.validate()?;
+        Ok(kl)
     }
 }
 /// A segment
 ///
 /// A segment consists of `n_mode` Karhunen-Loeve modes concatenated in the `modes` vector.
 /// Each mode is defined on a particular segment and the location of the modes
-/// are set with the 512x512 exit pupil `mask`
-#[derive(Debug)]
-pub struct Segment {
-    /// Segment modes
-    pub modes: Vec<f64>,
+/// are set with the 512x512 exit pupil `mask`.
+/// The modes are in double precision by default, see [Segment::cast] for other precisions
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(bound(
+    serialize = "T: na::Scalar + Serialize",
+    deserialize = "T: na::Scalar + Deserialize<'de>"
+))]
+pub struct Segment<T = f64> {
+    /// Segment modes, one mode per column
+    pub modes: na::DMatrix<T>,
     /// Number of modes
     pub n_mode: usize,
     /// Pupil mask for the segment
     pub mask: Vec<bool>,
     /// Modal coefficients
-    pub coefficients: Vec<f64>,
+    pub coefficients: Vec<T>,
     /// Segment modes pseudo-inverse
-    pub modes_pinv: na::DMatrix<f64>,
+    pub modes_pinv: na::DMatrix<T>,
+    /// Modes are known to be normalized such as `|m|=1`
+    pub normalized: bool,
+    /// Projection normalization factors `1/(|m|sqrt(n))` of the modes
+    ///
+    /// The factors must be updated with [Segment::update_norms] if the modes are modified
+    pub norms: Vec<T>,
 }
 impl From<KarhunenLoeve> for Segment {
     fn from(kl: KarhunenLoeve) -> Self {
-        let modes = na::DMatrix::from_column_slice(
-            kl.modes.len() / kl.n_mode,
-            kl.n_mode,
-            kl.modes.as_slice(),
-        );
-        let modes_pinv = modes
-            .pseudo_inverse(0f64)
-            .map_err(|e| GlaoError::PseudoInverse(e.into()))
-            .expect("pseudo-inverse failed");
-        Self {
-            modes: kl.modes,
-            n_mode: kl.n_mode,
-            mask: kl.mask,
-            coefficients: Vec::new(),
-            modes_pinv,
-        }
+        Segment::new(kl.n_mode, kl.modes, kl.mask)
     }
 }
-impl Segment {
-    /// Creates a new segment
+impl<T: Float> Segment<T> {
+    /// Returns a copy of the segment with the modes, their pseudo-inverse and the coefficients converted into `U`
     ///
-    /// The modes `m` are normalized  such as `|m|=1`
-    pub fn new(n_mode: usize, modes: Vec<f64>, mask: Vec<bool>) -> Self {
-        let mat_modes =
-            na::DMatrix::from_column_slice(modes.len() / n_mode, n_mode, modes.as_slice());
-        let modes_pinv = mat_modes
-            .pseudo_inverse(0f64)
-            .map_err(|e| GlaoError::PseudoInverse(e.into()))
-            .expect("pseudo-inverse failed");
-        Self {
-            modes,
-            n_mode,
-            mask,
-            coefficients: Vec::new(),
-            modes_pinv,
+    /// A single precision segment is derived from a double precision one with `segment.cast::<f32>()`
+    pub fn cast<U: na::RealField + Copy>(&self) -> Segment<U> {
+        let convert = |x: &T| U::from_subset(&x.to_subset_unchecked());
+        Segment {
+            modes: self.modes.map(|x| convert(&x)),
+            n_mode: self.n_mode,
+            mask: self.mask.clone(),
+            coefficients: self.coefficients.iter().map(convert).collect(),
+            modes_pinv: self.modes_pinv.map(|x| convert(&x)),
+            normalized: self.normalized,
+            norms: self.norms.iter().map(convert).collect(),
         }
     }
+    /// Returns the modes concatenated in a single vector
+    pub fn modes(&self) -> &[T] {
+        self.modes.as_slice()
+    }
+    /// Returns a `n_point x n_mode` view of the modes
+    pub fn modes_matrix(&self) -> na::DMatrixSlice<'_, T> {
+        self.modes.slice((0, 0), self.modes.shape())
+    }
     /// Returns the number of points within the segment
     pub fn n_point(&self) -> usize {
-        self.modes.len() / self.n_mode
+        self.modes.nrows()
     }
     /// Returns the number of points within the mask
     pub fn n_in_mask(&self) -> usize {
         self.mask.iter().filter_map(|m| m.then(|| 1)).sum()
     }
     /// Applies the mask
-    pub fn masked(&self, data: &[f64]) -> Vec<f64> {
+    pub fn masked(&self, data: &[T]) -> Vec<T> {
         self.mask
             .iter()
             .zip(data)
@@ -106,7 +291,7 @@ impl Segment {
     ///
     /// The old data has the same size than the mask array.
     /// The new data is the size of the masked area
-    pub fn masked_replace(&self, old_data: &mut [f64], new_data: Vec<f64>) {
+    pub fn masked_replace(&self, old_data: &mut [T], new_data: Vec<T>) {
         self.mask
             .iter()
             .zip(old_data)
@@ -121,7 +306,7 @@ impl Segment {
     ///
     /// The old data has the same size than the mask array.
     /// The new data is the size of the masked area
-    pub fn masked_sub(&self, old_data: &mut [f64], new_data: Vec<f64>) {
+    pub fn masked_sub(&self, old_data: &mut [T], new_data: Vec<T>) {
         self.mask
             .iter()
             .zip(old_data)
@@ -132,96 +317,459 @@ impl Segment {
                 *old -= new;
             });
     }
+    /// Projects `opd` on all the modes with a single matrix-vector product `Mᵀd`
+    ///
+    /// The coefficients are the same as [Segment::project]
+    pub fn project_gemv(&mut self, opd: &[T]) -> Result<&mut Self> {
+        let n = self.n_point();
+        let m = self.mask.len();
+        let masked_opd = match opd.len() {
+            l if l == m => Ok(na::DVector::from_column_slice(self.masked(opd).as_slice())),
+            l if l == n => Ok(na::DVector::from_column_slice(opd)),
+            _ => Err(GlaoError::Projection),
+        }?;
+        let b = self.modes.tr_mul(&masked_opd);
+        self.coefficients = self
+            .norms
+            .iter()
+            .zip(b.iter())
+            .map(|(&norm, &b)| norm * b)
+            .collect();
+        Ok(self)
+    }
+    pub fn least_square(&mut self, opd: &[T]) -> Result<&mut Self> {
+        let n = self.n_point();
+        let m = self.mask.len();
+        let masked_opd = match opd.len() {
+            l if l == m => Ok(na::DVector::from_column_slice(self.masked(opd).as_slice())),
+            l if l == n => Ok(na::DVector::from_column_slice(opd)),
+            _ => Err(GlaoError::Projection),
+        }?;
+        self.coefficients = (&self.modes_pinv * masked_opd).as_slice().to_vec();
+        Ok(self)
+    }
+    pub fn least_square_out(&self, opd: &[T]) -> Result<Vec<T>> {
+        let n = self.n_point();
+        let m = self.mask.len();
+        let masked_opd = match opd.len() {
+            l if l == m => Ok(na::DVector::from_column_slice(self.masked(opd).as_slice())),
+            l if l == n => Ok(na::DVector::from_column_slice(opd)),
+            _ => Err(GlaoError::Projection),
+        }?;
+        let b = &self.modes_pinv * masked_opd;
+        Ok(b.as_slice().to_vec())
+    }
+    /// Returns the Gram matrix `MᵀM` of the modes
+    pub fn gram(&self) -> na::DMatrix<T> {
+        self.modes.tr_mul(&self.modes)
+    }
+    /// Computes the shape of the mirror segment from the modal `coefficients`
+    ///
+    /// The segment coefficients are left untouched
+    pub fn synthesize(&self, coefficients: &[T]) -> Vec<T> {
+        (&self.modes * na::DVector::from_column_slice(coefficients))
+            .as_slice()
+            .to_vec()
+    }
+    /// Creates a new segment
+    ///
+    /// The modes `m` are normalized  such as `|m|=1`
+    pub fn new(n_mode: usize, modes: Vec<T>, mask: Vec<bool>) -> Self {
+        Self::try_new(n_mode, modes, mask, T::zero()).expect("pseudo-inverse failed")
+    }
+    /// Creates a new segment, discarding the singular values of the modes smaller or equal to `tol`
+    ///
+    /// Returns [GlaoError::PseudoInverse] if the pseudo-inverse of the modes fails
+    pub fn try_new(n_mode: usize, modes: Vec<T>, mask: Vec<bool>, tol: T) -> Result<Self> {
+        let modes = na::DMatrix::from_vec(modes.len() / n_mode, n_mode, modes);
+        let modes_pinv = pseudo_inverse(modes.clone(), tol)?;
+        Ok(Self::with_pinv(modes, mask, modes_pinv))
+    }
+    /// Creates a new segment from the modes matrix and its pseudo-inverse
+    fn with_pinv(modes: na::DMatrix<T>, mask: Vec<bool>, modes_pinv: na::DMatrix<T>) -> Self {
+        let mut segment = Self {
+            n_mode: modes.ncols(),
+            modes,
+            mask,
+            coefficients: Vec::new(),
+            modes_pinv,
+            normalized: false,
+            norms: Vec::new(),
+        };
+        segment.update_norms();
+        segment
+    }
+    /// Creates a new segment from modes already normalized such as `|m|=1`
+    ///
+    /// The normalization of the modes is skipped in the projections,
+    /// use [Segment::is_normalized] to check the modes beforehand
+    pub fn new_prenormalized(n_mode: usize, modes: Vec<T>, mask: Vec<bool>) -> Self {
+        let mut segment = Self {
+            normalized: true,
+            ..Self::new(n_mode, modes, mask)
+        };
+        segment.update_norms();
+        segment
+    }
+    /// Checks that the modes, their pseudo-inverse and their normalization factors
+    /// are consistent with the mask and the number of modes
+    fn is_consistent(&self) -> bool {
+        let n_point = self.mask.iter().filter(|&&m| m).count();
+        self.modes.shape() == (n_point, self.n_mode)
+            && self.modes_pinv.shape() == (self.n_mode, n_point)
+            && self.norms.len() == self.n_mode
+    }
+    /// Checks if all the modes `m` verify `|m|=1` within `tol`
+    pub fn is_normalized(&self, tol: T) -> bool {
+        self.modes()
+            .chunks(self.n_point())
+            .all(|mode| (dot(mode, mode).sqrt() - T::one()).abs() <= tol)
+    }
+    /// Computes the projection normalization factors `1/(|m|sqrt(n))` of the modes
+    pub fn update_norms(&mut self) -> &mut Self {
+        let n = self.n_point();
+        let n_t: T = na::convert(n as f64);
+        self.norms = if self.normalized {
+            vec![n_t.sqrt().recip(); self.n_mode]
+        } else {
+            self.modes()
+                .chunks(n)
+                .map(|mode| (dot(mode, mode) * n_t).sqrt().recip())
+                .collect()
+        };
+        self
+    }
     /// Projects `opd` on all the modes
-    pub fn project(&mut self, opd: &[f64]) -> Result<&mut Self> {
+    ///
+    /// The projections on each mode are computed in parallel
+    pub fn project(&mut self, opd: &[T]) -> Result<&mut Self> {
         let n = self.n_point();
-        let m: usize = 512 * 512;
+        let m = self.mask.len();
         self.coefficients = match opd.len() {
             l if l == m => {
                 let masked_opd: Vec<_> = self.masked(opd);
                 Ok(self
-                    .modes
-                    .chunks(n)
-                    .map(|mode| {
-                        let norm = (mode.iter().map(|x| x * x).sum::<f64>() * n as f64)
-                            .sqrt()
-                            .recip();
-                        norm * mode
-                            .iter()
-                            .zip(masked_opd.iter())
-                            .fold(0f64, |a, (&x, y)| a + x * y)
-                    })
+                    .modes()
+                    .par_chunks(n)
+                    .zip(&self.norms)
+                    .map(|(mode, &norm)| norm * dot(mode, &masked_opd))
                     .collect())
             }
             l if l == n => Ok(self
-                .modes
-                .chunks(n)
-                .map(|mode| {
-                    let norm = (mode.iter().map(|x| x * x).sum::<f64>() * n as f64)
-                        .sqrt()
-                        .recip();
-                    norm * mode.iter().zip(opd).fold(0f64, |a, (&x, &y)| a + x * y)
-                })
+                .modes()
+                .par_chunks(n)
+                .zip(&self.norms)
+                .map(|(mode, &norm)| norm * dot(mode, opd))
                 .collect()),
             _ => Err(GlaoError::Projection),
         }?;
         Ok(self)
     }
-    pub fn least_square(&mut self, opd: &[f64]) -> Result<&mut Self> {
+    /// Projects `opd` on the modes given by the indices `idx`
+    ///
+    /// The coefficients of the other modes are set to zero
+    pub fn project_subset(
+        &mut self,
+        opd: &[T],
+        idx: impl Iterator<Item = usize> + Clone,
+    ) -> Result<&mut Self> {
         let n = self.n_point();
-        let m: usize = 512 * 512;
+        let m = self.mask.len();
         let masked_opd = match opd.len() {
-            l if l == m => Ok(na::DVector::from_column_slice(self.masked(opd).as_slice())),
-            l if l == n => Ok(na::DVector::from_column_slice(opd)),
+            l if l == m => Ok(self.masked(opd)),
+            l if l == n => Ok(opd.to_vec()),
             _ => Err(GlaoError::Projection),
         }?;
-        self.coefficients = (&self.modes_pinv * masked_opd).as_slice().to_vec();
+        let mut coefficients = vec![T::zero(); self.n_mode];
+        for i in idx {
+            if i >= self.n_mode {
+                return Err(GlaoError::Projection);
+            }
+            let mode = &self.modes()[i * n..(i + 1) * n];
+            coefficients[i] = self.norms[i] * dot(mode, &masked_opd);
+        }
+        self.coefficients = coefficients;
         Ok(self)
     }
-    pub fn project_out(&self, opd: &[f64]) -> Result<Vec<f64>> {
+    /// Projects `opd` on all the modes and zeroes the coefficients beyond the first `n` modes
+    pub fn project_first_n(&mut self, opd: &[T], n: usize) -> Result<&mut Self> {
+        self.project(opd)?;
+        self.coefficients
+            .iter_mut()
+            .skip(n)
+            .for_each(|c| *c = T::zero());
+        Ok(self)
+    }
+    pub fn project_out(&self, opd: &[T]) -> Result<Vec<T>> {
+        let mut b = vec![T::zero(); self.n_mode];
+        self.project_out_into(opd, &mut b)?;
+        Ok(b)
+    }
+    /// Projects `opd` on all the modes and writes the coefficients into `out`
+    ///
+    /// `out` must have as many elements as there are modes
+    pub fn project_out_into(&self, opd: &[T], out: &mut [T]) -> Result<()> {
+        if out.len() != self.n_mode {
+            return Err(GlaoError::NMode(self.n_mode, out.len()));
+        }
         let n = self.n_point();
-        let m: usize = 512 * 512;
+        let m = self.mask.len();
         match opd.len() {
             l if l == m => {
                 let masked_opd: Vec<_> = self.masked(opd);
-                Ok(self
-                    .modes
-                    .chunks(n)
-                    .map(|mode| {
-                        let norm = (mode.iter().map(|x| x * x).sum::<f64>() * n as f64)
-                            .sqrt()
-                            .recip();
-                        norm * mode
-                            .iter()
-                            .zip(masked_opd.iter())
-                            .fold(0f64, |a, (&x, y)| a + x * y)
-                    })
-                    .collect())
+                out.par_iter_mut()
+                    .zip(self.modes().par_chunks(n).zip(&self.norms))
+                    .for_each(|(b, (mode, &norm))| *b = norm * dot(mode, &masked_opd));
             }
-            l if l == n => Ok(self
-                .modes
-                .chunks(n)
-                .map(|mode| {
-                    let norm = (mode.iter().map(|x| x * x).sum::<f64>() * n as f64)
-                        .sqrt()
-                        .recip();
-                    norm * mode.iter().zip(opd).fold(0f64, |a, (&x, &y)| a + x * y)
-                })
-                .collect()),
-            _ => Err(GlaoError::Projection),
+            l if l == n => out
+                .par_iter_mut()
+                .zip(self.modes().par_chunks(n).zip(&self.norms))
+                .for_each(|(b, (mode, &norm))| *b = norm * dot(mode, opd)),
+            _ => return Err(GlaoError::Projection),
         }
+        Ok(())
     }
-    pub fn least_square_out(&self, opd: &[f64]) -> Result<Vec<f64>> {
+    /// Projects each of the `opds` on all the modes
+    ///
+    /// The projections are computed with a single matrix product `MᵀD`
+    /// with `D` the matrix of the masked `opds`
+    pub fn project_out_batch(&self, opds: &[&[T]]) -> Result<Vec<Vec<T>>> {
         let n = self.n_point();
-        let m: usize = 512 * 512;
+        let m = self.mask.len();
+        let masked_opds = opds
+            .iter()
+            .map(|opd| match opd.len() {
+                l if l == m => Ok(self.masked(opd)),
+                l if l == n => Ok(opd.to_vec()),
+                _ => Err(GlaoError::Projection),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        let b = self
+            .modes
+            .tr_mul(&na::DMatrix::from_vec(n, opds.len(), masked_opds));
+        Ok(b.column_iter()
+            .map(|b| {
+                b.iter()
+                    .zip(&self.norms)
+                    .map(|(&b, &norm)| norm * b)
+                    .collect()
+            })
+            .collect())
+    }
+}
+impl Segment {
+    /// Creates a new segment with a Tikhonov regularized pseudo-inverse of the modes
+    ///
+    /// The pseudo-inverse is `(MᵀM + λI)⁻¹Mᵀ`, damping the small singular values of the modes
+    /// in [Segment::least_square]
+    pub fn with_regularization(kl: KarhunenLoeve, lambda: f64) -> Self {
+        let modes = na::DMatrix::from_vec(kl.modes.len() / kl.n_mode, kl.n_mode, kl.modes);
+        let modes_pinv = regularized_pseudo_inverse(&modes, lambda).expect("pseudo-inverse failed");
+        Self::with_pinv(modes, kl.mask, modes_pinv)
+    }
+    /// Weighted least square fit of the modes to `opd`
+    ///
+    /// The coefficients are the solution of `(MᵀWM)b = MᵀWd` with `W` the diagonal matrix of the `weights`,
+    /// the weights are either sampled on the 512x512 grid or within the segment mask
+    pub fn weighted_least_square(&mut self, opd: &[f64], weights: &[f64]) -> Result<&mut Self> {
+        let n = self.n_point();
+        let m = self.mask.len();
+        let masked = |data: &[f64]| match data.len() {
+            l if l == m => Ok(na::DVector::from_column_slice(self.masked(data).as_slice())),
+            l if l == n => Ok(na::DVector::from_column_slice(data)),
+            _ => Err(GlaoError::Projection),
+        };
+        let (masked_opd, masked_weights) = (masked(opd)?, masked(weights)?);
+        let mut weighted_modes = self.modes.clone();
+        weighted_modes
+            .column_iter_mut()
+            .for_each(|mut mode| mode.component_mul_assign(&masked_weights));
+        let b = self
+            .modes
+            .tr_mul(&weighted_modes)
+            .cholesky()
+            .ok_or(GlaoError::Projection)?
+            .solve(&weighted_modes.tr_mul(&masked_opd));
+        self.coefficients = b.as_slice().to_vec();
+        Ok(self)
+    }
+    /// Least square fit of the modes to `opd` with a thin QR factorization of the modes
+    ///
+    /// The factorization is computed on demand, making it a lower memory alternative
+    /// to [Segment::least_square_out] for segments without a pseudo-inverse
+    pub fn least_square_qr(&self, opd: &[f64]) -> Result<Vec<f64>> {
+        let n = self.n_point();
+        let m = self.mask.len();
         let masked_opd = match opd.len() {
             l if l == m => Ok(na::DVector::from_column_slice(self.masked(opd).as_slice())),
             l if l == n => Ok(na::DVector::from_column_slice(opd)),
             _ => Err(GlaoError::Projection),
         }?;
-        let b = &self.modes_pinv * masked_opd;
+        let qr = self.modes.clone().qr();
+        let qt_opd = qr.q().tr_mul(&masked_opd);
+        let b = qr
+            .r()
+            .solve_upper_triangular(&qt_opd)
+            .ok_or(GlaoError::Projection)?;
         Ok(b.as_slice().to_vec())
     }
+    /// Returns the smallest number of modes that brings the residual WFE RMS of `opd` below `target_rms`
+    ///
+    /// The residual variance after correcting the first `k` modes is derived from the
+    /// `opd` mean sum square minus the cumulative sum of the squared projected modal coefficients.
+    /// Returns `None` if the target is not reached with all the modes
+    pub fn modes_for_residual(&self, opd: &[f64], target_rms: f64) -> Result<Option<usize>> {
+        let b = self.project_out(opd)?;
+        let masked_opd = match opd.len() {
+            l if l == self.mask.len() => self.masked(opd),
+            _ => opd.to_vec(),
+        };
+        let ss = masked_opd.iter().map(|x| x * x).sum::<f64>() / masked_opd.len() as f64;
+        let target_var = target_rms * target_rms;
+        if ss < target_var {
+            return Ok(Some(0));
+        }
+        let mut residual_var = ss;
+        Ok(b.iter()
+            .position(|b| {
+                residual_var -= b * b;
+                residual_var.max(0f64) < target_var
+            })
+            .map(|k| k + 1))
+    }
+    /// Resamples the modes onto a new `new_rows`x`new_cols` grid with the pupil `new_mask`
+    ///
+    /// The modes are bilinearly interpolated from the 512x512 grid of the current mask,
+    /// the corners of both grids being aligned.
+    /// Samples falling at the edge of the current mask are interpolated from the
+    /// neighboring pixels within the mask only.
+    /// The pseudo-inverse of the resampled modes is recomputed.
+    pub fn resample_modes(
+        &self,
+        new_mask: &[bool],
+        new_rows: usize,
+        new_cols: usize,
+    ) -> Result<Segment> {
+        if new_mask.len() != new_rows * new_cols {
+            return Err(GlaoError::Dimensions(new_mask.len(), new_rows, new_cols));
+        }
+        let (rows, cols) = (512, 512);
+        let scale = |new_n: usize, n: usize| {
+            if new_n > 1 {
+                (n - 1) as f64 / (new_n - 1) as f64
+            } else {
+                0f64
+            }
+        };
+        let (row_scale, col_scale) = (scale(new_rows, rows), scale(new_cols, cols));
+        let n = self.n_point();
+        let modes: Vec<f64> = self
+            .modes()
+            .chunks(n)
+            .flat_map(|mode| {
+                let mut grid = vec![f64::NAN; rows * cols];
+                self.masked_replace(&mut grid, mode.to_vec());
+                new_mask
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &m)| m)
+                    .map(|(k, _)| {
+                        let y = ((k / new_cols) as f64 * row_scale).min((rows - 1) as f64);
+                        let x = ((k % new_cols) as f64 * col_scale).min((cols - 1) as f64);
+                        let (w, v) = bilinear_weights(rows, cols, x, y)
+                            .into_iter()
+                            .flatten()
+                            .map(|(k, w)| (w, grid[k]))
+                            .filter(|(_, v)| !v.is_nan())
+                            .fold((0f64, 0f64), |(sw, sv), (w, v)| (sw + w, sv + w * v));
+                        if w > 0f64 {
+                            v / w
+                        } else {
+                            0f64
+                        }
+                    })
+                    .collect::<Vec<f64>>()
+            })
+            .collect();
+        Ok(Segment::new(self.n_mode, modes, new_mask.to_vec()))
+    }
+    /// Zeroes the modal coefficients which magnitude is less than `min_abs`
+    pub fn threshold_coefficients(&mut self, min_abs: f64) -> &mut Self {
+        self.coefficients
+            .iter_mut()
+            .filter(|c| c.abs() < min_abs)
+            .for_each(|c| *c = 0f64);
+        self
+    }
+    /// Returns the condition number of the modes, the ratio of the largest to the smallest singular value
+    ///
+    /// The condition number is infinite if the modes are linearly dependent
+    pub fn condition_number(&self) -> f64 {
+        let s = self.modes.singular_values();
+        s.max() / s.min()
+    }
+    /// Returns the largest magnitude of the off-diagonal elements of the normalized [Segment::gram] matrix
+    ///
+    /// The normalized Gram matrix element `(i,j)` is the cosine of the angle between the modes `i` and `j`,
+    /// it is 0 for orthogonal modes
+    pub fn max_offdiagonal(&self) -> f64 {
+        let gram = self.gram();
+        let diag = gram.diagonal();
+        let mut max = 0f64;
+        for j in 0..self.n_mode {
+            for i in 0..j {
+                max = max.max(gram[(i, j)].abs() / (diag[i] * diag[j]).sqrt());
+            }
+        }
+        max
+    }
+    /// Returns the wavefront error of each mode
+    ///
+    /// The coefficients are expected to be those of [Segment::project],
+    /// i.e. `cᵢ=m·d/(|m|sqrt(n))` which is already the RMS of the component of the OPD `d` along the mode `m`,
+    /// so the wavefront error of mode `i` is `|cᵢ|`.
+    /// The coefficients of [Segment::least_square] are modal amplitudes instead,
+    /// their wavefront error is `|cᵢ|` times the mode RMS
+    pub fn modal_wfe(&self) -> Vec<f64> {
+        self.coefficients.iter().map(|c| c.abs()).collect()
+    }
+    /// Returns the cumulative wavefront error of the modes
+    ///
+    /// The element `i` is the root sum square of the [Segment::modal_wfe] of the first `i+1` modes
+    pub fn cumulative_wfe(&self) -> Vec<f64> {
+        self.modal_wfe()
+            .into_iter()
+            .scan(0f64, |ss, wfe| {
+                *ss += wfe * wfe;
+                Some(ss.sqrt())
+            })
+            .collect()
+    }
+    /// Orthonormalizes the modes with the modified Gram-Schmidt algorithm
+    ///
+    /// The modes are normalized such as `|m|=1` and the pseudo-inverse is recomputed.
+    /// A mode linearly dependent on the previous ones is set to zero
+    pub fn orthonormalize(&mut self) -> &mut Self {
+        let n = self.n_point();
+        for i in 0..self.n_mode {
+            let (previous, modes) = self.modes.as_mut_slice().split_at_mut(i * n);
+            let mode = &mut modes[..n];
+            for q in previous.chunks(n) {
+                let r = dot(q, mode);
+                mode.iter_mut().zip(q).for_each(|(m, q)| *m -= r * q);
+            }
+            let norm = dot(mode, mode).sqrt();
+            if norm > f64::EPSILON {
+                mode.iter_mut().for_each(|m| *m /= norm);
+            } else {
+                mode.iter_mut().for_each(|m| *m = 0f64);
+            }
+        }
+        self.modes_pinv = pseudo_inverse(self.modes.clone(), 0f64).expect("pseudo-inverse failed");
+        self.normalized = true;
+        self.update_norms()
+    }
     /// Computes the shape of the mirror segment
     ///
     /// Uses either all the modes or a specified set in an [Iterator]
@@ -230,7 +778,7 @@ impl Segment {
         if let Some(idx) = idx {
             let modes = idx
                 .clone()
-                .map(|idx| self.modes.chunks(n).nth(idx).unwrap());
+                .map(|idx| self.modes().chunks(n).nth(idx).unwrap());
             let coefficients = idx.map(|idx| self.coefficients[idx]);
             modes
                 .zip(coefficients)
@@ -239,7 +787,7 @@ impl Segment {
                     w
                 })
         } else {
-            self.modes
+            self.modes()
                 .chunks(n)
                 .zip(&self.coefficients)
                 .fold(vec![0f64; n], |mut w, (m, c)| {
@@ -251,7 +799,7 @@ impl Segment {
 }
 
 /// A single ASM
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum ASM {
     S1(Segment),
     S2(Segment),
@@ -276,18 +824,102 @@ impl ASM {
         .to_string()
     }
     pub fn from_bin(sid: usize) -> Result<Self> {
-        let kl = KarhunenLoeve::from_bin(sid)?;
+        Self::from_kl(sid, KarhunenLoeve::from_bin(sid)?)
+    }
+    /// Saves the segment, including the modes pseudo-inverse, into a [bincode] file
+    pub fn to_bin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        bincode::serialize_into(file, self)?;
+        Ok(())
+    }
+    /// Loads a segment saved with [ASM::to_bin]
+    ///
+    /// Returns [GlaoError::Bin2Asm] if the dimensions of the modes, of the pseudo-inverse
+    /// and of the mask are inconsistent
+    pub fn load_bin<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        let asm: Self = bincode::deserialize_from(file)?;
+        use ASM::*;
+        let consistent = match &asm {
+            S1(segment) => segment.is_consistent(),
+            S2(segment) => segment.is_consistent(),
+            S3(segment) => segment.is_consistent(),
+            S4(segment) => segment.is_consistent(),
+            S5(segment) => segment.is_consistent(),
+            S6(segment) => segment.is_consistent(),
+            S7(segment) => segment.is_consistent(),
+        };
+        if consistent {
+            Ok(asm)
+        } else {
+            Err(GlaoError::Bin2Asm)
+        }
+    }
+    /// Loads the segment asynchronously
+    ///
+    /// The modes pseudo-inverse is computed on the blocking thread pool
+    #[cfg(feature = "async")]
+    pub async fn from_bin_async(sid: usize) -> Result<Self> {
+        let kl = KarhunenLoeve::from_bin_async(sid).await?;
+        tokio::task::spawn_blocking(move || Self::from_kl(sid, kl)).await?
+    }
+    fn from_kl(sid: usize, kl: KarhunenLoeve) -> Result<Self> {
+        Self::from_segment(sid, kl.into())
+    }
+    fn from_segment(sid: usize, segment: Segment) -> Result<Self> {
         match sid {
-            id if id == 1 => Ok(ASM::S1(kl.into())),
-            id if id == 2 => Ok(ASM::S2(kl.into())),
-            id if id == 3 => Ok(ASM::S3(kl.into())),
-            id if id == 4 => Ok(ASM::S4(kl.into())),
-            id if id == 5 => Ok(ASM::S5(kl.into())),
-            id if id == 6 => Ok(ASM::S6(kl.into())),
-            id if id == 7 => Ok(ASM::S7(kl.into())),
+            id if id == 1 => Ok(ASM::S1(segment)),
+            id if id == 2 => Ok(ASM::S2(segment)),
+            id if id == 3 => Ok(ASM::S3(segment)),
+            id if id == 4 => Ok(ASM::S4(segment)),
+            id if id == 5 => Ok(ASM::S5(segment)),
+            id if id == 6 => Ok(ASM::S6(segment)),
+            id if id == 7 => Ok(ASM::S7(segment)),
             _ => Err(GlaoError::Bin2Asm),
         }
     }
+    /// Loads the segment with the modes pseudo-inverse cached in `cache_dir`
+    ///
+    /// The pseudo-inverse is read from the file `M2S{sid}.pinv.bin` in `cache_dir`.
+    /// If the file is missing or was computed for a different set of modes, detected from the modes
+    /// dimensions and a hash of their values,
+    /// the pseudo-inverse is computed and the file is (re)written
+    pub fn from_bin_cached(sid: usize, cache_dir: &Path) -> Result<Self> {
+        Self::from_kl_cached(sid, KarhunenLoeve::from_bin(sid)?, cache_dir)
+    }
+    fn from_kl_cached(sid: usize, kl: KarhunenLoeve, cache_dir: &Path) -> Result<Self> {
+        let path = cache_dir.join(format!("M2S{sid}.pinv.bin"));
+        let n_point = kl.modes.len() / kl.n_mode;
+        let hash = modes_hash(&kl.modes);
+        let cached = File::open(&path)
+            .ok()
+            .and_then(|file| bincode::deserialize_from::<_, PinvCache>(file).ok())
+            .filter(|cache| {
+                cache.n_mode == kl.n_mode
+                    && cache.modes_len == kl.modes.len()
+                    && cache.modes_hash == hash
+                    && cache.modes_pinv.shape() == (kl.n_mode, n_point)
+            });
+        let segment = match cached {
+            Some(cache) => {
+                let modes = na::DMatrix::from_vec(n_point, kl.n_mode, kl.modes);
+                Segment::with_pinv(modes, kl.mask, cache.modes_pinv)
+            }
+            None => {
+                let segment = Segment::from(kl);
+                std::fs::create_dir_all(cache_dir)?;
+                let cache = PinvCache {
+                    n_mode: segment.n_mode,
+                    modes_len: segment.modes.len(),
+                    modes_hash: hash,
+                    modes_pinv: segment.modes_pinv.clone(),
+                };
+                bincode::serialize_into(File::create(&path)?, &cache)?;
+                segment
+            }
+        };
+        Self::from_segment(sid, segment)
+    }
     /// Projects `opd` on all the modes
     pub fn project(&mut self, opd: &[f64]) -> Result<&mut Self> {
         use ASM::*;
@@ -302,6 +934,52 @@ impl ASM {
         }?;
         Ok(self)
     }
+    /// Projects `opd` on all the modes with a single matrix-vector product
+    pub fn project_gemv(&mut self, opd: &[f64]) -> Result<&mut Self> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.project_gemv(opd),
+            S2(segment) => segment.project_gemv(opd),
+            S3(segment) => segment.project_gemv(opd),
+            S4(segment) => segment.project_gemv(opd),
+            S5(segment) => segment.project_gemv(opd),
+            S6(segment) => segment.project_gemv(opd),
+            S7(segment) => segment.project_gemv(opd),
+        }?;
+        Ok(self)
+    }
+    /// Projects `opd` on all the modes and zeroes the coefficients beyond the first `n` modes
+    pub fn project_first_n(&mut self, opd: &[f64], n: usize) -> Result<&mut Self> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.project_first_n(opd, n),
+            S2(segment) => segment.project_first_n(opd, n),
+            S3(segment) => segment.project_first_n(opd, n),
+            S4(segment) => segment.project_first_n(opd, n),
+            S5(segment) => segment.project_first_n(opd, n),
+            S6(segment) => segment.project_first_n(opd, n),
+            S7(segment) => segment.project_first_n(opd, n),
+        }?;
+        Ok(self)
+    }
+    /// Projects `opd` on the modes given by the indices `idx`
+    pub fn project_subset(
+        &mut self,
+        opd: &[f64],
+        idx: impl Iterator<Item = usize> + Clone,
+    ) -> Result<&mut Self> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.project_subset(opd, idx),
+            S2(segment) => segment.project_subset(opd, idx),
+            S3(segment) => segment.project_subset(opd, idx),
+            S4(segment) => segment.project_subset(opd, idx),
+            S5(segment) => segment.project_subset(opd, idx),
+            S6(segment) => segment.project_subset(opd, idx),
+            S7(segment) => segment.project_subset(opd, idx),
+        }?;
+        Ok(self)
+    }
     pub fn least_square(&mut self, opd: &[f64]) -> Result<&mut Self> {
         use ASM::*;
         match self {
@@ -327,6 +1005,32 @@ impl ASM {
             S7(segment) => segment.project_out(opd),
         }
     }
+    /// Projects `opd` on all the modes and writes the coefficients into `out`
+    pub fn project_out_into(&self, opd: &[f64], out: &mut [f64]) -> Result<()> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.project_out_into(opd, out),
+            S2(segment) => segment.project_out_into(opd, out),
+            S3(segment) => segment.project_out_into(opd, out),
+            S4(segment) => segment.project_out_into(opd, out),
+            S5(segment) => segment.project_out_into(opd, out),
+            S6(segment) => segment.project_out_into(opd, out),
+            S7(segment) => segment.project_out_into(opd, out),
+        }
+    }
+    /// Projects each of the `opds` on all the modes
+    pub fn project_out_batch(&self, opds: &[&[f64]]) -> Result<Vec<Vec<f64>>> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.project_out_batch(opds),
+            S2(segment) => segment.project_out_batch(opds),
+            S3(segment) => segment.project_out_batch(opds),
+            S4(segment) => segment.project_out_batch(opds),
+            S5(segment) => segment.project_out_batch(opds),
+            S6(segment) => segment.project_out_batch(opds),
+            S7(segment) => segment.project_out_batch(opds),
+        }
+    }
     pub fn least_square_out(&self, opd: &[f64]) -> Result<Vec<f64>> {
         use ASM::*;
         match self {
@@ -339,6 +1043,32 @@ impl ASM {
             S7(segment) => segment.least_square_out(opd),
         }
     }
+    /// Least square fit of the modes to `opd` with a thin QR factorization of the modes
+    pub fn least_square_qr(&self, opd: &[f64]) -> Result<Vec<f64>> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.least_square_qr(opd),
+            S2(segment) => segment.least_square_qr(opd),
+            S3(segment) => segment.least_square_qr(opd),
+            S4(segment) => segment.least_square_qr(opd),
+            S5(segment) => segment.least_square_qr(opd),
+            S6(segment) => segment.least_square_qr(opd),
+            S7(segment) => segment.least_square_qr(opd),
+        }
+    }
+    /// Returns the number of modes
+    pub fn n_mode(&self) -> usize {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.n_mode,
+            S2(segment) => segment.n_mode,
+            S3(segment) => segment.n_mode,
+            S4(segment) => segment.n_mode,
+            S5(segment) => segment.n_mode,
+            S6(segment) => segment.n_mode,
+            S7(segment) => segment.n_mode,
+        }
+    }
     /// Returns the number of points within the segment
     pub fn n_point(&self) -> usize {
         use ASM::*;
@@ -365,6 +1095,45 @@ impl ASM {
             S7(segment) => segment.n_in_mask(),
         }
     }
+    /// Returns the wavefront error of each mode, see [Segment::modal_wfe]
+    pub fn modal_wfe(&self) -> Vec<f64> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.modal_wfe(),
+            S2(segment) => segment.modal_wfe(),
+            S3(segment) => segment.modal_wfe(),
+            S4(segment) => segment.modal_wfe(),
+            S5(segment) => segment.modal_wfe(),
+            S6(segment) => segment.modal_wfe(),
+            S7(segment) => segment.modal_wfe(),
+        }
+    }
+    /// Returns the cumulative wavefront error of the modes, see [Segment::cumulative_wfe]
+    pub fn cumulative_wfe(&self) -> Vec<f64> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.cumulative_wfe(),
+            S2(segment) => segment.cumulative_wfe(),
+            S3(segment) => segment.cumulative_wfe(),
+            S4(segment) => segment.cumulative_wfe(),
+            S5(segment) => segment.cumulative_wfe(),
+            S6(segment) => segment.cumulative_wfe(),
+            S7(segment) => segment.cumulative_wfe(),
+        }
+    }
+    /// Returns the condition number of the segment modes
+    pub fn condition_number(&self) -> f64 {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.condition_number(),
+            S2(segment) => segment.condition_number(),
+            S3(segment) => segment.condition_number(),
+            S4(segment) => segment.condition_number(),
+            S5(segment) => segment.condition_number(),
+            S6(segment) => segment.condition_number(),
+            S7(segment) => segment.condition_number(),
+        }
+    }
     /// Returns the segment shape
     pub fn shape(&self, idx: Option<impl Iterator<Item = usize> + Clone>) -> Vec<f64> {
         use ASM::*;
@@ -378,6 +1147,39 @@ impl ASM {
             S7(segment) => segment.shape(idx),
         }
     }
+    /// Returns the segment shape from the modal `coefficients`
+    pub fn synthesize(&self, coefficients: &[f64]) -> Vec<f64> {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.synthesize(coefficients),
+            S2(segment) => segment.synthesize(coefficients),
+            S3(segment) => segment.synthesize(coefficients),
+            S4(segment) => segment.synthesize(coefficients),
+            S5(segment) => segment.synthesize(coefficients),
+            S6(segment) => segment.synthesize(coefficients),
+            S7(segment) => segment.synthesize(coefficients),
+        }
+    }
+    /// Returns the area of the mask for a given `pixel_scale`
+    ///
+    /// The area unit is the square of the `pixel_scale` unit
+    pub fn mask_area(&self, pixel_scale: f64) -> f64 {
+        self.n_in_mask() as f64 * pixel_scale * pixel_scale
+    }
+    /// Zeroes the segment modal coefficients which magnitude is less than `min_abs`
+    pub fn threshold_coefficients(&mut self, min_abs: f64) -> &mut Self {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.threshold_coefficients(min_abs),
+            S2(segment) => segment.threshold_coefficients(min_abs),
+            S3(segment) => segment.threshold_coefficients(min_abs),
+            S4(segment) => segment.threshold_coefficients(min_abs),
+            S5(segment) => segment.threshold_coefficients(min_abs),
+            S6(segment) => segment.threshold_coefficients(min_abs),
+            S7(segment) => segment.threshold_coefficients(min_abs),
+        };
+        self
+    }
     /// Returns the segment modal coefficients
     pub fn coefficients(&self) -> &[f64] {
         use ASM::*;
@@ -391,19 +1193,48 @@ impl ASM {
             S7(segment) => segment.coefficients.as_slice(),
         }
     }
+    /// Sets the segment modal coefficients
+    pub fn set_coefficients(&mut self, coefficients: Vec<f64>) -> &mut Self {
+        use ASM::*;
+        match self {
+            S1(segment) => segment.coefficients = coefficients,
+            S2(segment) => segment.coefficients = coefficients,
+            S3(segment) => segment.coefficients = coefficients,
+            S4(segment) => segment.coefficients = coefficients,
+            S5(segment) => segment.coefficients = coefficients,
+            S6(segment) => segment.coefficients = coefficients,
+            S7(segment) => segment.coefficients = coefficients,
+        };
+        self
+    }
     /// Returns the segment modes
     pub fn modes(&self) -> &[f64] {
         use ASM::*;
         match self {
-            S1(segment) => segment.modes.as_slice(),
-            S2(segment) => segment.modes.as_slice(),
-            S3(segment) => segment.modes.as_slice(),
-            S4(segment) => segment.modes.as_slice(),
-            S5(segment) => segment.modes.as_slice(),
-            S6(segment) => segment.modes.as_slice(),
-            S7(segment) => segment.modes.as_slice(),
+            S1(segment) => segment.modes(),
+            S2(segment) => segment.modes(),
+            S3(segment) => segment.modes(),
+            S4(segment) => segment.modes(),
+            S5(segment) => segment.modes(),
+            S6(segment) => segment.modes(),
+            S7(segment) => segment.modes(),
         }
     }
+    /// Returns the `idx`th mode sampled on the 512x512 grid
+    ///
+    /// Pixels outside the segment mask are set to NaN
+    pub fn mode_as_opd(&self, idx: usize) -> Result<OPD> {
+        let n = self.n_point();
+        let n_mode = self.modes().len() / n;
+        let mode = self
+            .modes()
+            .chunks(n)
+            .nth(idx)
+            .ok_or(GlaoError::ModeIndex(idx, n_mode))?;
+        let mut data = vec![f64::NAN; self.mask().len()];
+        self.masked_replace(&mut data, mode.to_vec());
+        Ok(OPD::new(data))
+    }
     /// Returns the segment mask
     pub fn mask(&self) -> &[bool] {
         use ASM::*;
@@ -455,6 +1286,16 @@ impl ASM {
 mod tests {
     use super::*;
 
+    /// Returns `n_mode` cosine modes sampled on `n_point` points
+    fn dct_modes(n_point: usize, n_mode: usize) -> Vec<f64> {
+        (0..n_point * n_mode)
+            .map(|k| {
+                let (i, j) = ((k % n_point) as f64, (k / n_point) as f64);
+                (std::f64::consts::PI * j * (i + 0.5) / n_point as f64).cos()
+            })
+            .collect()
+    }
+
     #[test]
     fn kl_from_bin() {
         let kl = KarhunenLoeve::from_bin(1).unwrap();
@@ -463,6 +1304,15 @@ mod tests {
         assert_eq!(kl.mask.len(), 512 * 512);
     }
 
+    #[test]
+    fn kl_from_bin_with() {
+        assert!(KarhunenLoeve::from_bin_with(1, 500).is_ok());
+        assert!(matches!(
+            KarhunenLoeve::from_bin_with(1, 200),
+            Err(GlaoError::NMode(200, 500))
+        ));
+    }
+
     /*
         #[test]
         fn py2rs() {
@@ -521,6 +1371,19 @@ mod tests {
         println!("b: {:?}", &asm.coefficients()[..5]);
     }
 
+    #[test]
+    fn project_gemv() {
+        let mut asm = ASM::from_bin(1).unwrap();
+        let opd: Vec<_> = (0..asm.n_point())
+            .map(|k| (k as f64 * 1e-3).sin() * 1e-6)
+            .collect();
+        let b = asm.project(&opd).unwrap().coefficients().to_vec();
+        let b_gemv = asm.project_gemv(&opd).unwrap().coefficients().to_vec();
+        b.into_iter()
+            .zip(b_gemv)
+            .for_each(|(b, b_gemv)| assert!((b - b_gemv).abs() < 1e-15));
+    }
+
     #[test]
     fn project_out() {
         let mut asm = ASM::from_bin(1).unwrap();
@@ -536,4 +1399,632 @@ mod tests {
         let b = asm.least_square_out(&modes).unwrap();
         println!("b: {:?}", &b[..5]);
     }
+
+    #[test]
+    fn threshold_coefficients() {
+        let modes = vec![1., 0., 0., 0., 1., 0., 0., 0., 1.];
+        let mut segment = Segment::new(3, modes, vec![true; 3]);
+        segment.coefficients = vec![1e-3, 0.5, -2.];
+        let mut asm = ASM::S1(segment);
+        let shape = asm.shape(Option::<std::iter::Once<usize>>::None);
+        asm.threshold_coefficients(1e-2);
+        assert_eq!(asm.coefficients().iter().filter(|c| **c != 0.).count(), 2);
+        let thresholded_shape = asm.shape(Option::<std::iter::Once<usize>>::None);
+        assert_ne!(shape, thresholded_shape);
+        assert_eq!(thresholded_shape, vec![0., 0.5, -2.]);
+    }
+
+    #[test]
+    fn modes_for_residual() {
+        let modes = vec![
+            1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.,
+        ];
+        let segment = Segment::new(4, modes, vec![true; 4]);
+        let opd = vec![4., 2., 1., 0.5];
+        assert_eq!(segment.modes_for_residual(&opd, 3.).unwrap(), Some(0));
+        assert_eq!(segment.modes_for_residual(&opd, 1.2).unwrap(), Some(1));
+        assert_eq!(segment.modes_for_residual(&opd, 0.5).unwrap(), Some(3));
+        assert_eq!(segment.modes_for_residual(&opd, 0.).unwrap(), None);
+    }
+
+    #[test]
+    fn resample_modes() {
+        let mask: Vec<_> = (0..512 * 512)
+            .map(|k| (200..210).contains(&(k / 512)) && (300..310).contains(&(k % 512)))
+            .collect();
+        let n = mask.iter().filter(|&&m| m).count();
+        let modes: Vec<_> = (0..n)
+            .map(|k| 1. + (k / 10) as f64)
+            .chain((0..n).map(|k| (k % 10) as f64 - 4.5))
+            .collect();
+        let segment = Segment::new(2, modes.clone(), mask.clone());
+        let resampled = segment.resample_modes(&mask, 512, 512).unwrap();
+        assert_eq!(resampled.n_point(), n);
+        resampled
+            .modes
+            .iter()
+            .zip(&modes)
+            .for_each(|(r, m)| assert!((r - m).abs() < 1e-12));
+        assert!(segment.resample_modes(&mask, 256, 512).is_err());
+    }
+
+    #[test]
+    fn project_first_n() {
+        let modes: Vec<_> = (0..16)
+            .map(|k| if k / 4 == k % 4 { 1f64 } else { 0f64 })
+            .collect();
+        let mut asm = ASM::S1(Segment::new(4, modes, vec![true; 4]));
+        asm.project_first_n(&[4., 3., 2., 1.], 2).unwrap();
+        assert_eq!(asm.coefficients(), &[2., 1.5, 0., 0.]);
+    }
+
+    #[test]
+    fn residual_first_n() {
+        use crate::{ASMS, OPD};
+        let mut asms: Vec<_> = [1000, 2000]
+            .into_iter()
+            .map(|offset| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + 4).contains(&k))
+                    .collect();
+                let modes: Vec<_> = (0..16)
+                    .map(|k| if k / 4 == k % 4 { 2f64 } else { 0f64 })
+                    .collect();
+                ASM::S1(Segment::new(4, modes, mask))
+            })
+            .collect();
+        let mut data = vec![f64::NAN; 512 * 512];
+        data[1000..1004].copy_from_slice(&[4., -3., 2., 1.]);
+        data[2000..2004].copy_from_slice(&[-2., 1.5, 1., -0.5]);
+        let opd = OPD::new(data);
+        let rms: Vec<_> = (0..=4)
+            .map(|n| asms.residual_first_n(&opd, n).unwrap().rms())
+            .collect();
+        assert!(rms.windows(2).all(|r| r[1] < r[0]));
+        assert!(rms[4] < 1e-12);
+    }
+
+    #[test]
+    fn mask_area() {
+        use crate::ASMS;
+        let asms: Vec<_> = [10, 20]
+            .into_iter()
+            .map(|n| {
+                let mask: Vec<_> = (0..100).map(|k| k < n).collect();
+                ASM::S1(Segment::new(1, vec![1f64; n], mask))
+            })
+            .collect();
+        assert!((asms[0].mask_area(0.5) - 2.5).abs() < 1e-12);
+        assert!((asms.total_area(0.5) - 7.5).abs() < 1e-12);
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn simd_dot() {
+        for n in [0, 3, 4, 21382] {
+            let a: Vec<_> = (0..n).map(|k| (k as f64 * 0.37).sin()).collect();
+            let b: Vec<_> = (0..n).map(|k| (k as f64 * 0.11).cos() * 1e-6).collect();
+            let (simd, scalar) = (dot(&a, &b), dot_scalar(&a, &b));
+            assert!((simd - scalar).abs() <= 1e-12 * scalar.abs().max(1e-12));
+        }
+    }
+
+    #[cfg(feature = "lapack")]
+    #[test]
+    fn lapack_pseudo_inverse() {
+        let (n_point, n_mode) = (200, 10);
+        let modes = dct_modes(n_point, n_mode);
+        let mat_modes = na::DMatrix::from_column_slice(n_point, n_mode, &modes);
+        let default_pinv = mat_modes.clone().pseudo_inverse(0f64).unwrap();
+        let segment = Segment::new(n_mode, modes, vec![true; n_point]);
+        let opd: Vec<_> = (0..n_point).map(|k| (k as f64 * 0.05).cos()).collect();
+        let b = segment.least_square_out(&opd).unwrap();
+        let b_default = default_pinv * na::DVector::from_column_slice(&opd);
+        b.iter()
+            .zip(b_default.iter())
+            .for_each(|(b, b_default)| assert!((b - b_default).abs() < 1e-6));
+    }
+
+    #[test]
+    fn ill_conditioned_pseudo_inverse() {
+        let n_point = 200;
+        // the second mode is the first one within the rounding noise
+        let dct = dct_modes(n_point, 3);
+        let first: Vec<_> = dct[n_point..2 * n_point]
+            .iter()
+            .map(|m| 1. + 0.1 * m)
+            .collect();
+        let second: Vec<_> = first
+            .iter()
+            .zip(&dct[2 * n_point..])
+            .map(|(m, d)| m + 1e-15 * d)
+            .collect();
+        let modes = [first, second].concat();
+        let segment = Segment::new(2, modes, vec![true; n_point]);
+        assert!(segment.modes_pinv.iter().all(|x| x.abs() < 1.));
+        let opd = segment.modes()[..n_point].to_vec();
+        let b = segment.least_square_out(&opd).unwrap();
+        assert!((b[0] - 0.5).abs() < 1e-6 && (b[1] - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parallel_project_out() {
+        let (n_point, n_mode) = (1000, 50);
+        let modes: Vec<_> = (0..n_point * n_mode)
+            .map(|k| (k as f64 * 0.013).sin())
+            .collect();
+        let segment = Segment::new(n_mode, modes, vec![true; n_point]);
+        let opd: Vec<_> = (0..n_point).map(|k| (k as f64 * 0.07).cos()).collect();
+        let sequential: Vec<_> = segment
+            .modes()
+            .chunks(n_point)
+            .map(|mode| {
+                let norm = (dot(mode, mode) * n_point as f64).sqrt().recip();
+                norm * dot(mode, &opd)
+            })
+            .collect();
+        assert_eq!(segment.project_out(&opd).unwrap(), sequential);
+    }
+
+    #[test]
+    fn kl_cached() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let n_load = AtomicUsize::new(0);
+        let load = || {
+            n_load.fetch_add(1, Ordering::SeqCst);
+            Ok(KarhunenLoeve {
+                modes: vec![1f64; 4],
+                n_mode: 1,
+                mask: vec![true; 4],
+            })
+        };
+        let sid = usize::MAX;
+        let kl = KarhunenLoeve::cached_or_load(sid, load).unwrap();
+        let kl_again = KarhunenLoeve::cached_or_load(sid, load).unwrap();
+        assert_eq!(n_load.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&kl, &kl_again));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn kl_from_bin_async() {
+        let kl = KarhunenLoeve::from_bin_async(1).await.unwrap();
+        let kl_sync = KarhunenLoeve::from_bin(1).unwrap();
+        assert_eq!(kl.n_mode, kl_sync.n_mode);
+        assert_eq!(kl.modes, kl_sync.modes);
+        assert_eq!(kl.mask, kl_sync.mask);
+    }
+
+    #[test]
+    fn kl_validate() {
+        let mut kl = KarhunenLoeve {
+            modes: vec![1f64; 12],
+            n_mode: 3,
+            mask: (0..10).map(|k| k < 4).collect(),
+        };
+        assert!(kl.validate().is_ok());
+        kl.modes.truncate(11);
+        assert!(matches!(kl.validate(), Err(GlaoError::ModeCount(11, 3, 4))));
+        kl.modes.truncate(9);
+        assert!(matches!(kl.validate(), Err(GlaoError::ModeCount(9, 3, 4))));
+        kl.modes.clear();
+        assert!(kl.validate().is_err());
+    }
+
+    #[test]
+    fn prenormalized() {
+        let n_point = 64;
+        let modes = dct_modes(n_point, 3);
+        let unit_modes: Vec<_> = modes
+            .chunks(n_point)
+            .flat_map(|mode| {
+                let norm = dot(mode, mode).sqrt();
+                mode.iter().map(move |x| x / norm)
+            })
+            .collect();
+        let opd: Vec<_> = (0..n_point).map(|k| (k as f64 * 0.2).sin()).collect();
+        let segment = Segment::new(3, modes, vec![true; n_point]);
+        assert!(!segment.is_normalized(1e-9));
+        let unit_segment = Segment::new_prenormalized(3, unit_modes, vec![true; n_point]);
+        assert!(unit_segment.is_normalized(1e-9));
+        segment
+            .project_out(&opd)
+            .unwrap()
+            .into_iter()
+            .zip(unit_segment.project_out(&opd).unwrap())
+            .for_each(|(b, unit_b)| assert!((b - unit_b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn orthonormalize() {
+        let modes = vec![1f64, 1., 0., 0., 1., 0.5, 0.5, 0.];
+        let mut segment = Segment::new(2, modes, vec![true; 4]);
+        assert!((segment.gram()[(0, 1)]).abs() > 0.1);
+        let identity = na::DMatrix::<f64>::identity(2, 2);
+        assert!((segment.orthonormalize().gram() - identity).abs().max() < 1e-12);
+        assert!(segment.is_normalized(1e-12));
+    }
+
+    #[test]
+    fn least_square_qr() {
+        let n_point = 100;
+        let modes: Vec<_> = dct_modes(n_point, 5)
+            .into_iter()
+            .enumerate()
+            .map(|(k, mode)| mode + 0.1 * (k % n_point) as f64 / n_point as f64)
+            .collect();
+        let segment = Segment::new(5, modes, vec![true; n_point]);
+        let opd: Vec<_> = (0..n_point).map(|k| (k as f64 * 0.3).sin()).collect();
+        segment
+            .least_square_qr(&opd)
+            .unwrap()
+            .into_iter()
+            .zip(segment.least_square_out(&opd).unwrap())
+            .for_each(|(qr, svd)| assert!((qr - svd).abs() < 1e-9));
+    }
+
+    #[test]
+    fn try_new() {
+        let n_point = 20;
+        // the second mode is twice the first one
+        let modes: Vec<_> = (0..2 * n_point)
+            .map(|k| (1 + k / n_point) as f64 * (k % n_point) as f64)
+            .collect();
+        let segment = Segment::try_new(2, modes, vec![true; n_point], 1e-6).unwrap();
+        let opd: Vec<_> = (0..n_point).map(|k| k as f64).collect();
+        let b = segment.least_square_out(&opd).unwrap();
+        assert!((b[0] - 0.2).abs() < 1e-9 && (b[1] - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn with_regularization() {
+        let n_point = 50;
+        // two nearly degenerate modes
+        let modes: Vec<_> = (0..2 * n_point)
+            .map(|k| {
+                let (i, j) = ((k % n_point) as f64, (k / n_point) as f64);
+                (0.1 * i).sin() + 1e-6 * j * (0.3 * i).cos()
+            })
+            .collect();
+        let kl = |modes: Vec<f64>| KarhunenLoeve {
+            modes,
+            n_mode: 2,
+            mask: vec![true; n_point],
+        };
+        let opd: Vec<_> = (0..n_point).map(|k| (0.2 * k as f64).cos()).collect();
+        let mut segment = Segment::from(kl(modes.clone()));
+        let mut regularized = Segment::with_regularization(kl(modes), 1e-3);
+        let norm = |s: &mut Segment| {
+            s.least_square(&opd)
+                .unwrap()
+                .coefficients
+                .iter()
+                .map(|x| x * x)
+                .sum::<f64>()
+                .sqrt()
+        };
+        assert!(norm(&mut regularized) < 1e-3 * norm(&mut segment));
+    }
+
+    #[test]
+    fn update_norms() {
+        let mut segment = Segment::new(2, vec![1., 1., 1., 1., 0., 3., 4., 0.], vec![true; 4]);
+        assert_eq!(segment.norms, vec![0.25, 0.1]);
+        segment.orthonormalize();
+        assert_eq!(segment.norms, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn weighted_least_square() {
+        let n_point = 6;
+        let modes = vec![1., 1., 1., 1., 1., 1., 0., 1., 2., 3., 4., 5.];
+        let mut segment = Segment::new(2, modes, vec![true; n_point]);
+        // the last pixel is an outlier
+        let opd = vec![1., 3., 5., 7., 9., 100.];
+        let weights = vec![1., 1., 1., 1., 1., 0.];
+        let b = segment
+            .weighted_least_square(&opd, &weights)
+            .unwrap()
+            .coefficients
+            .clone();
+        assert!((b[0] - 1.).abs() < 1e-9 && (b[1] - 2.).abs() < 1e-9);
+        let b_lsq = segment.least_square_out(&opd).unwrap();
+        segment
+            .weighted_least_square(&opd, &[1.; 6])
+            .unwrap()
+            .coefficients
+            .iter()
+            .zip(b_lsq)
+            .for_each(|(w, b)| assert!((w - b).abs() < 1e-9));
+        assert!(segment.weighted_least_square(&opd, &[1.; 5]).is_err());
+    }
+
+    #[test]
+    fn project_grid_size() {
+        let mask: Vec<_> = (0..16).map(|k| (5..9).contains(&k)).collect();
+        let segment = Segment::new(1, vec![1f64; 4], mask);
+        let opd: Vec<_> = (0..16).map(|k| k as f64).collect();
+        assert_eq!(segment.project_out(&opd).unwrap(), vec![6.5]);
+        assert!(segment.project_out(&opd[..8]).is_err());
+    }
+
+    #[test]
+    fn asm_to_bin() {
+        let mask: Vec<_> = (0..16).map(|k| (5..9).contains(&k)).collect();
+        let modes = vec![1., 1., 1., 1., 0., 1., 2., 3.];
+        let mut asm = ASM::S3(Segment::new(2, modes, mask));
+        asm.project(&[0.5, 1.5, 2.5, 3.5]).unwrap();
+        let path = std::env::temp_dir().join("asm_to_bin.bin");
+        asm.to_bin(&path).unwrap();
+        let loaded = ASM::load_bin(&path).unwrap();
+        assert_eq!(loaded.tag(), "M2S3");
+        assert_eq!(loaded.coefficients(), asm.coefficients());
+        assert_eq!(loaded.modes(), asm.modes());
+        if let (ASM::S3(loaded), ASM::S3(segment)) = (&loaded, &mut asm) {
+            assert_eq!(loaded.modes_pinv, segment.modes_pinv);
+            segment.modes_pinv = na::DMatrix::zeros(2, 3);
+        }
+        asm.to_bin(&path).unwrap();
+        assert!(matches!(ASM::load_bin(&path), Err(GlaoError::Bin2Asm)));
+    }
+
+    #[test]
+    fn from_kl_cached() {
+        let cache_dir = std::env::temp_dir().join("glao_pinv_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let kl = |modes: Vec<f64>| KarhunenLoeve {
+            n_mode: 2,
+            modes,
+            mask: vec![true; 4],
+        };
+        let modes = vec![1., 1., 1., 1., 0., 1., 2., 3.];
+        let asm = ASM::from_kl_cached(2, kl(modes.clone()), &cache_dir).unwrap();
+        assert!(cache_dir.join("M2S2.pinv.bin").exists());
+        let cached = ASM::from_kl_cached(2, kl(modes.clone()), &cache_dir).unwrap();
+        let opd = [0.5, 1.5, 2.5, 3.5];
+        assert_eq!(
+            cached.least_square_out(&opd).unwrap(),
+            asm.least_square_out(&opd).unwrap()
+        );
+        // a stale cache is rebuilt
+        let modes: Vec<_> = modes.into_iter().chain([1., 0., 0., 1.]).collect();
+        let asm = ASM::from_kl_cached(
+            2,
+            KarhunenLoeve {
+                n_mode: 3,
+                ..kl(modes)
+            },
+            &cache_dir,
+        )
+        .unwrap();
+        assert_eq!(asm.least_square_out(&opd).unwrap().len(), 3);
+        let cache: PinvCache =
+            bincode::deserialize_from(File::open(cache_dir.join("M2S2.pinv.bin")).unwrap())
+                .unwrap();
+        assert_eq!(cache.n_mode, 3);
+        // a cache of modes with the same dimensions is rebuilt
+        let modes = vec![1., 1., 1., 1., 0., 1., 2., 3., 1., 0., 0., -1.];
+        let asm = ASM::from_kl_cached(
+            2,
+            KarhunenLoeve {
+                n_mode: 3,
+                ..kl(modes.clone())
+            },
+            &cache_dir,
+        )
+        .unwrap();
+        let segment = Segment::new(3, modes.clone(), vec![true; 4]);
+        asm.least_square_out(&opd)
+            .unwrap()
+            .into_iter()
+            .zip(segment.least_square_out(&opd).unwrap())
+            .for_each(|(b, expected)| assert!((b - expected).abs() < 1e-12));
+        let cache: PinvCache =
+            bincode::deserialize_from(File::open(cache_dir.join("M2S2.pinv.bin")).unwrap())
+                .unwrap();
+        assert_eq!(cache.modes_hash, modes_hash(&modes));
+    }
+
+    #[test]
+    fn condition_number() {
+        let segment = Segment::new(2, vec![3., 0., 0., 0., 0., 0.5, 0., 0.], vec![true; 4]);
+        assert!((segment.condition_number() - 6.).abs() < 1e-12);
+        let asm = ASM::S1(Segment::new(
+            2,
+            vec![1., 1., 1., 1., 2., 2., 2., 2.],
+            vec![true; 4],
+        ));
+        assert!(asm.condition_number() > 1e12);
+    }
+
+    #[test]
+    fn project_subset() {
+        use crate::{ASMS, OPD};
+        let n_point = 32;
+        let modes = dct_modes(n_point, 4);
+        let mask: Vec<_> = (0..512 * 512).map(|k| k < n_point).collect();
+        let mut asms = vec![ASM::S1(Segment::new(4, modes, mask))];
+        let opd = OPD::new(
+            (0..512 * 512)
+                .map(|k| {
+                    if k < n_point {
+                        (k as f64).sin()
+                    } else {
+                        f64::NAN
+                    }
+                })
+                .collect(),
+        );
+        let b = asms.project_out(&opd).unwrap();
+        asms.project_subset(&opd, [1, 3].into_iter()).unwrap();
+        assert_eq!(asms[0].coefficients(), &[0., b[1], 0., b[3]]);
+        assert!(asms.project_subset(&opd, 3..5).is_err());
+    }
+
+    #[test]
+    fn mode_as_opd() {
+        let mask: Vec<_> = (0..512 * 512).map(|k| (1000..1002).contains(&k)).collect();
+        let asm = ASM::S2(Segment::new(2, vec![1., 2., 3., 4.], mask));
+        let opd = asm.mode_as_opd(1).unwrap();
+        assert_eq!(opd.map()[1000..1002], [3., 4.]);
+        assert_eq!(opd.no_nan_opd().count(), 2);
+        assert!(matches!(
+            asm.mode_as_opd(2),
+            Err(GlaoError::ModeIndex(2, 2))
+        ));
+    }
+
+    #[test]
+    fn max_offdiagonal() {
+        let segment = Segment::new(2, vec![2., 0., 1., 1.], vec![true; 2]);
+        assert!((segment.max_offdiagonal() - 0.5f64.sqrt()).abs() < 1e-12);
+        let segment = Segment::new(2, vec![3., 0., 0., 0.5], vec![true; 2]);
+        assert_eq!(segment.max_offdiagonal(), 0.);
+    }
+
+    #[test]
+    fn single_precision_segment() {
+        let n_point = 64;
+        let modes = dct_modes(n_point, 3);
+        let mut segment = Segment::new(3, modes, vec![true; n_point]);
+        let mut segment_f32: Segment<f32> = segment.cast();
+        let opd: Vec<_> = (0..n_point).map(|k| (k as f64 * 0.2).sin()).collect();
+        let opd_f32: Vec<_> = opd.iter().map(|&x| x as f32).collect();
+        segment.least_square(&opd).unwrap();
+        segment_f32.least_square(&opd_f32).unwrap();
+        segment
+            .coefficients
+            .iter()
+            .zip(&segment_f32.coefficients)
+            .for_each(|(&b, &b_f32)| assert!((b - b_f32 as f64).abs() < 1e-5));
+        segment.project_gemv(&opd).unwrap();
+        segment_f32.project_gemv(&opd_f32).unwrap();
+        segment
+            .coefficients
+            .iter()
+            .zip(&segment_f32.coefficients)
+            .for_each(|(&b, &b_f32)| assert!((b - b_f32 as f64).abs() < 1e-5));
+        let modes_f32: Vec<_> = segment.modes().iter().map(|&x| x as f32).collect();
+        let mut segment_f32 = Segment::<f32>::new(3, modes_f32, vec![true; n_point]);
+        segment.project(&opd).unwrap();
+        segment_f32.project(&opd_f32).unwrap();
+        segment
+            .coefficients
+            .iter()
+            .zip(&segment_f32.coefficients)
+            .for_each(|(&b, &b_f32)| assert!((b - b_f32 as f64).abs() < 1e-5));
+        let b_f32 = segment_f32.least_square_out(&opd_f32).unwrap();
+        segment
+            .least_square_out(&opd)
+            .unwrap()
+            .iter()
+            .zip(&b_f32)
+            .for_each(|(&b, &b_f32)| assert!((b - b_f32 as f64).abs() < 1e-5));
+    }
+
+    #[test]
+    fn modes_matrix() {
+        let modes: Vec<_> = (0..12).map(|k| k as f64).collect();
+        let segment = Segment::new(3, modes, vec![true; 4]);
+        let view = segment.modes_matrix();
+        assert_eq!(view.shape(), (4, 3));
+        assert_eq!(
+            view.column(1).iter().cloned().collect::<Vec<_>>(),
+            segment.modes().chunks(4).nth(1).unwrap()
+        );
+    }
+
+    #[test]
+    fn project_out_batch() {
+        use crate::{ASMS, OPD};
+        let asms: Vec<_> = [1000, 2000]
+            .into_iter()
+            .map(|offset| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + 8).contains(&k))
+                    .collect();
+                let modes: Vec<_> = (0..24).map(|k| ((k * k) as f64 * 0.1).sin()).collect();
+                ASM::S1(Segment::new(3, modes, mask))
+            })
+            .collect();
+        let opds: Vec<_> = (0..3)
+            .map(|i| {
+                OPD::new(
+                    (0..512 * 512)
+                        .map(|k| ((k + i) as f64 * 0.37).cos())
+                        .collect(),
+                )
+            })
+            .collect();
+        let opd_refs: Vec<_> = opds.iter().collect();
+        let mut out = Vec::new();
+        for opd in &opds {
+            asms.project_out_into(opd, &mut out).unwrap();
+            assert_eq!(out, asms.project_out(opd).unwrap());
+        }
+        let batch = asms.project_out_batch(&opd_refs).unwrap();
+        assert_eq!(batch.len(), 3);
+        for (b_batch, opd) in batch.into_iter().zip(&opds) {
+            let b = asms.project_out(opd).unwrap();
+            assert_eq!(b_batch.len(), b.len());
+            b_batch
+                .into_iter()
+                .zip(b)
+                .for_each(|(b_batch, b)| assert!((b_batch - b).abs() < 1e-12));
+        }
+    }
+
+    #[test]
+    fn variance_contributions() {
+        use crate::{ASMS, OPD};
+        let asms: Vec<_> = [1000, 2000, 3000]
+            .into_iter()
+            .map(|offset| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + 4).contains(&k))
+                    .collect();
+                ASM::S1(Segment::new(1, vec![1f64; 4], mask))
+            })
+            .collect();
+        let mut data = vec![f64::NAN; 512 * 512];
+        data[1000..1004].copy_from_slice(&[1e-9, -1e-9, 1e-9, -1e-9]);
+        data[2000..2004].copy_from_slice(&[1e-6, -1e-6, 1e-6, -1e-6]);
+        data[3000..3004].copy_from_slice(&[0., 1e-9, 0., -1e-9]);
+        let contributions = asms.variance_contributions(&OPD::new(data));
+        assert!((contributions.iter().sum::<f64>() - 1.).abs() < 1e-12);
+        assert!(contributions[1] > 0.999);
+    }
+
+    #[test]
+    fn modal_wfe() {
+        let n_point = 256;
+        let modes: Vec<_> = dct_modes(n_point, 3)
+            .chunks(n_point)
+            .flat_map(|mode| {
+                let norm = dot(mode, mode).sqrt();
+                mode.iter().map(move |m| m / norm)
+            })
+            .collect();
+        let opd: Vec<_> = modes.chunks(n_point).zip([-3e-6, 0., 4e-6]).fold(
+            vec![0f64; n_point],
+            |mut opd, (mode, a)| {
+                opd.iter_mut().zip(mode).for_each(|(o, m)| *o += a * m);
+                opd
+            },
+        );
+        let mut asm = ASM::S1(Segment::new(3, modes, vec![true; n_point]));
+        asm.project(&opd).unwrap();
+        // unit-norm modes have a RMS of 1/sqrt(n)
+        let rms = (n_point as f64).sqrt().recip();
+        asm.modal_wfe()
+            .into_iter()
+            .zip([3e-6 * rms, 0., 4e-6 * rms])
+            .for_each(|(wfe, expected)| assert!((wfe - expected).abs() < 1e-18));
+        asm.cumulative_wfe()
+            .into_iter()
+            .zip([3e-6 * rms, 3e-6 * rms, 5e-6 * rms])
+            .for_each(|(wfe, expected)| assert!((wfe - expected).abs() < 1e-18));
+        let opd_rms = (dot(&opd, &opd) / n_point as f64).sqrt();
+        assert!((asm.cumulative_wfe()[2] - opd_rms).abs() < 1e-18);
+    }
 }
@@ -1,13 +1,58 @@
+use clap::{Parser, ValueEnum};
 use glao_error_budget::{OpdRecord, ASM, ASMS, OPD};
 use parse_monitors::cfd;
 use rayon::prelude::*;
 use std::fs::File;
 
+/// Modal coefficients estimation method
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Method {
+    /// Projection of the OPD on the modes
+    Project,
+    /// Least square fit of the modes to the OPD
+    Lstsq,
+}
+
+/// Dome seeing OPD modal analysis
+#[derive(Debug, Parser)]
+struct Cli {
+    /// CFD case, all the CFD cases if not given
+    #[arg(long)]
+    case: Option<String>,
+    /// Modal coefficients estimation method
+    #[arg(long, value_enum, default_value_t = Method::Lstsq)]
+    method: Method,
+    /// Records file name, saved in each CFD case directory
+    #[arg(long, default_value = "domeseeing-lstsq_kl.bin")]
+    out: String,
+    /// Weights the segments with their mask areas
+    #[arg(long)]
+    mask_area_ratios: bool,
+}
+
 fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     println!("Assembling the ASM segments ...");
     let asms: Vec<ASM> = ASMS::from_bins()?;
+    let ratios = if cli.mask_area_ratios {
+        asms.mask_area_ratios()
+    } else {
+        asms.area_ratios()
+    };
 
-    for cfd_case in cfd::Baseline::<2021>::mount().into_iter() {
+    let cfd_cases: Vec<_> = cfd::Baseline::<2021>::mount()
+        .into_iter()
+        .filter(|cfd_case| {
+            cli.case
+                .as_ref()
+                .is_none_or(|case| cfd_case.to_string() == *case)
+        })
+        .collect();
+    if cfd_cases.is_empty() {
+        anyhow::bail!("unknown CFD case: {}", cli.case.unwrap_or_default());
+    }
+
+    for cfd_case in cfd_cases {
         println!("CFD case: {cfd_case}");
         let files: Vec<_> = cfd::CfdDataFile::<2021>::OpticalPathDifference
             .glob(cfd_case)?
@@ -22,23 +67,29 @@ fn main() -> anyhow::Result<()> {
                     .map(|x| x.to_str().unwrap())
                     .unwrap()
                     .into();
-                let mut opd = OPD::from_npz(file)?;
+                let mut opd: OPD = OPD::from_npz(file)?;
                 opd.mask_with(&asms.mask()).zero_mean();
-                //let modal_coefficients = asms.project_out(&opd);
-                let modal_coefficients = asms.least_square_out(&opd);
+                let modal_coefficients = match cli.method {
+                    Method::Project => asms.project_out(&opd)?,
+                    Method::Lstsq => asms.least_square_out(&opd)?,
+                };
                 Ok(OpdRecord {
                     file: filename,
                     var: opd.var(),
-                    segment_sum_square: asms.iter().map(|asm| opd.masked_ss(asm.mask())).collect(),
+                    segment_sum_square: asms
+                        .iter()
+                        .map(|asm| opd.masked_rss(asm.mask()).powi(2))
+                        .collect(),
                     modal_coefficients,
-                    ratios: asms.area_ratios(),
+                    ratios: ratios.clone(),
+                    segment_var: asms.iter().map(|asm| opd.masked_var(asm.mask())).collect(),
                 })
             })
             .collect::<anyhow::Result<Vec<OpdRecord>>>()?;
 
         let path = cfd::Baseline::<2021>::path()
             .join(cfd_case.to_string())
-            .join("domeseeing-lstsq_kl.bin");
+            .join(&cli.out);
         let record_file = File::create(path)?;
         bincode::serialize_into(record_file, &records)?;
     }
@@ -48,27 +99,24 @@ fn main() -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use glao_error_budget::{combine_segment_wfe, records_from_bin};
     #[test]
     fn record() {
-        let records: Vec<OpdRecord> =
-            bincode::deserialize_from(File::open("domeseeing_kl.bin").unwrap()).unwrap();
-        println!("OPD STD: {:.0}nm", records[0].var.sqrt() * 1e9);
+        let records: Vec<OpdRecord> = records_from_bin("domeseeing_kl.bin").unwrap();
+        println!("OPD STD: {:.0}nm", records[0].rms() * 1e9);
         println!(
             "OPD segment RSS: {:.0?}nm",
             records[0]
-                .segment_sum_square
+                .segment_rms()
                 .iter()
-                .map(|x| x.sqrt() * 1e9)
+                .map(|x| x * 1e9)
                 .collect::<Vec<f64>>()
         );
-        let r = records[0].ratios.iter();
-        let ss = records[0]
-            .segment_sum_square
-            .iter()
-            .zip(r.clone())
-            .map(|(s, r)| s * r)
-            .sum::<f64>();
-        println!("OPD STD: {:.0}nm", ss.sqrt() * 1e9);
+        let ratios = &records[0].ratios;
+        println!(
+            "OPD STD: {:.0}nm",
+            combine_segment_wfe(&records[0].segment_rms(), ratios) * 1e9
+        );
         let b_ss: Vec<_> = records[0]
             .modal_coefficients
             .chunks(500)
@@ -78,14 +126,15 @@ mod tests {
             "OPD KL RSS: {:.0?}nm",
             b_ss.iter().map(|x| x.sqrt() * 1e9).collect::<Vec<f64>>()
         );
-        let res_var = records[0]
+        let res_rss: Vec<_> = records[0]
             .segment_sum_square
             .iter()
             .zip(&b_ss)
-            .map(|(x, y)| (x - y).abs())
-            .zip(r)
-            .map(|(x, r)| x * r)
-            .sum::<f64>();
-        println!("Residuals RSS {:.0}nm", res_var.sqrt() * 1e9);
+            .map(|(x, y)| (x - y).abs().sqrt())
+            .collect();
+        println!(
+            "Residuals RSS {:.0}nm",
+            combine_segment_wfe(&res_rss, ratios) * 1e9
+        );
     }
 }
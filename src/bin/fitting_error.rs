@@ -1,8 +1,7 @@
-use glao_error_budget::{OpdRecord, OpdStats};
+use glao_error_budget::{case_wfe_percentiles, records_from_bin, OpdRecord, OpdStats, OpdSummary};
 use nalgebra as na;
 use parse_monitors::cfd;
 use rayon::prelude::*;
-use std::fs::File;
 
 pub fn polyfit<T: na::RealField + Copy>(
     x_values: &[T],
@@ -42,8 +41,7 @@ fn main() -> anyhow::Result<()> {
             let path = cfd::Baseline::<2021>::path()
                 .join(&cfd_case)
                 .join("domeseeing-lstsq_kl.bin");
-            let record_file = File::open(path)?;
-            let records: Vec<OpdRecord> = bincode::deserialize_from(record_file)?;
+            let records: Vec<OpdRecord> = records_from_bin(path)?;
             let mean_std = records.mean_std() * 1e9;
             let mean_segment_rss: Vec<_> = records
                 .mean_segment_rss()
@@ -72,7 +70,16 @@ fn main() -> anyhow::Result<()> {
             ))
         })
         .collect();
-    results.unwrap().into_iter().for_each(
+    let results = results?;
+    let summaries: Vec<_> = results
+        .iter()
+        .map(|(cfd_case, mean_std, mean_segment_rss, ..)| OpdSummary {
+            cfd_case: cfd_case.clone(),
+            mean_std: *mean_std,
+            mean_segment_rss: mean_segment_rss.clone(),
+        })
+        .collect();
+    results.into_iter().for_each(
         |(
             cfd_case,
             mean_std,
@@ -134,5 +141,10 @@ fn main() -> anyhow::Result<()> {
             }
         },
     );
+    let p = [10., 50., 90.];
+    println!(
+        "Mean WFE percentiles {p:?}: {:.0?}nm",
+        case_wfe_percentiles(&summaries, &p)
+    );
     Ok(())
 }
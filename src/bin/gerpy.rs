@@ -1,31 +1,92 @@
 use glao_error_budget::asm::KarhunenLoeve;
-use serde_generate::SourceInstaller;
-use serde_reflection::{Tracer, TracerConfig};
-use std::path::Path;
+use serde_generate::{CodeGeneratorConfig, SourceInstaller};
+use serde_reflection::{Registry, Tracer, TracerConfig};
+use std::path::{Path, PathBuf};
 
-fn main() {
+/// Installs the module and the serde and bincode runtimes with `installer`
+fn install<I>(installer: I, config: &CodeGeneratorConfig, registry: &Registry) -> anyhow::Result<()>
+where
+    I: SourceInstaller<Error = Box<dyn std::error::Error>>,
+{
+    let to_anyhow = |e: Box<dyn std::error::Error>| anyhow::anyhow!("{e}");
+    installer
+        .install_module(config, registry)
+        .map_err(to_anyhow)?;
+    installer.install_bincode_runtime().map_err(to_anyhow)?;
+    installer.install_serde_runtime().map_err(to_anyhow)?;
+    Ok(())
+}
+
+/// Generates the bincode bindings of the traced types in the language `lang` into the directory `out`
+///
+/// `lang` is one of `python3` (or `python`), `cpp`, `rust`, `go`, `java`, `csharp`, `swift` or `typescript`
+fn generate_bindings(lang: &str, out: &Path) -> anyhow::Result<()> {
     // Start the tracing session.
     let mut tracer = Tracer::new(TracerConfig::default());
 
     // Trace the desired top-level type(s).
-    tracer.trace_simple_type::<KarhunenLoeve>().unwrap();
+    tracer
+        .trace_simple_type::<KarhunenLoeve>()
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
 
     // Also trace each enum type separately to fix any `MissingVariants` error.
     //tracer.trace_simple_type::<Segment>().unwrap();
 
-    // Obtain the registry of Serde formats and serialize it in YAML (for instance).
-    let registry = tracer.registry().unwrap();
+    // Obtain the registry of Serde formats.
+    let registry = tracer.registry().map_err(|e| anyhow::anyhow!("{e}"))?;
 
-    // Create Python class definitions.
-    let mut source = Vec::new();
-    let config = serde_generate::CodeGeneratorConfig::new("gerpy".to_string())
+    let config = CodeGeneratorConfig::new("gerpy".to_string())
         .with_encodings(vec![serde_generate::Encoding::Bincode]);
-    let generator = serde_generate::python3::CodeGenerator::new(&config);
-    generator.output(&mut source, &registry).unwrap();
+    let out = out.to_path_buf();
+    match lang {
+        "python" | "python3" => install(
+            serde_generate::python3::Installer::new(out, None),
+            &config,
+            &registry,
+        ),
+        "cpp" => install(serde_generate::cpp::Installer::new(out), &config, &registry),
+        "rust" => install(
+            serde_generate::rust::Installer::new(out),
+            &config,
+            &registry,
+        ),
+        "go" => install(
+            serde_generate::golang::Installer::new(out, None),
+            &config,
+            &registry,
+        ),
+        "java" => install(
+            serde_generate::java::Installer::new(out),
+            &config,
+            &registry,
+        ),
+        "csharp" => install(
+            serde_generate::csharp::Installer::new(out),
+            &config,
+            &registry,
+        ),
+        "swift" => install(
+            serde_generate::swift::Installer::new(out),
+            &config,
+            &registry,
+        ),
+        "typescript" => install(
+            serde_generate::typescript::Installer::new(out),
+            &config,
+            &registry,
+        ),
+        _ => anyhow::bail!("unsupported binding language: {lang}"),
+    }
+}
 
-    let path = Path::new("gerpy");
-    let install = serde_generate::python3::Installer::new(path.to_path_buf(), None);
-    install.install_module(&config, &registry).unwrap();
-    install.install_bincode_runtime().unwrap();
-    install.install_serde_runtime().unwrap();
+/// Generates the bincode bindings
+///
+/// Usage: `gerpy [LANG] [OUT]`, the bindings are generated in Python into the `gerpy` directory by default
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let lang = args.next().unwrap_or_else(|| "python3".to_string());
+    let out = args
+        .next()
+        .map_or_else(|| PathBuf::from("gerpy"), PathBuf::from);
+    generate_bindings(&lang, &out)
 }
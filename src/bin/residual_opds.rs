@@ -24,23 +24,14 @@ fn main() -> anyhow::Result<()> {
         })
         .map(|(cfd_case, last_opd_file)| {
             println!("CFD case: {cfd_case}");
-            let opd = OPD::from_npz(last_opd_file)?;
-            let size = (512, 512);
+            let opd: OPD = OPD::from_npz(last_opd_file)?;
             let filename = format!("{cfd_case}_domeseeing-micron.png");
-            let _: complot::Heatmap = (
-                (opd.map_10e(-6).as_slice(), size),
-                complot::complot!(filename),
-            )
-                .into();
+            OPD::new(opd.map_10e(-6)).write_heatmap_png(filename)?;
             //asms.project(&opd)?;
             asms.least_square(&opd)?;
             let residuals = &opd - &asms;
             let filename = format!("{cfd_case}_residuals-opd.png");
-            let _: complot::Heatmap = (
-                (residuals.map_10e(-9).as_slice(), size),
-                complot::complot!(filename),
-            )
-                .into();
+            OPD::new(residuals.map_10e(-9)).write_heatmap_png(filename)?;
             Ok(())
         })
         .collect();
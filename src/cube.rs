@@ -0,0 +1,339 @@
+use crate::{
+    opd::{into_f64_vec, write_npz},
+    GlaoError, Result, OPD,
+};
+use npyz::{npz, NpyFile, Order};
+use rayon::prelude::*;
+use std::{fs::File, io, path::Path};
+
+/// Pixel validity across the frames of an [OpdCube]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Validity {
+    /// A pixel is valid if it is valid in all the frames
+    #[default]
+    All,
+    /// A pixel is valid if it is valid in at least one frame
+    Any,
+}
+
+/// A time series of dome seeing OPD maps
+///
+/// All the frames are expected to be sampled on the same grid
+#[derive(Debug, Clone, Default)]
+pub struct OpdCube {
+    /// OPD maps
+    pub frames: Vec<OPD>,
+    /// Pixel validity across the frames
+    pub validity: Validity,
+}
+impl From<Vec<OPD>> for OpdCube {
+    fn from(frames: Vec<OPD>) -> Self {
+        Self::new(frames)
+    }
+}
+impl OpdCube {
+    /// Creates a new cube from the OPD `frames`
+    ///
+    /// A pixel is valid only if it is valid in all the frames
+    pub fn new(frames: Vec<OPD>) -> Self {
+        Self {
+            frames,
+            validity: Validity::All,
+        }
+    }
+    /// Sets the pixel validity across the frames
+    pub fn with_validity(self, validity: Validity) -> Self {
+        Self { validity, ..self }
+    }
+    /// Returns the number of frames
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    /// Checks if the cube has no frame
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+    /// Returns the `i`th frame
+    pub fn frame(&self, i: usize) -> &OPD {
+        &self.frames[i]
+    }
+    /// Writes the cube into a npz file
+    ///
+    /// The frames are saved in the `N x rows x cols` array `frames`
+    /// with the frames minimum and maximum in the `opd min` and `opd max` vectors.
+    /// The pixel scale of the first frame is saved in `pixel scale`, if known
+    pub fn to_npz<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (rows, cols) = self
+            .frames
+            .first()
+            .map_or((512, 512), |frame| frame.shape());
+        let frames: Vec<f64> = self
+            .frames
+            .iter()
+            .flat_map(|frame| frame.map().iter().cloned())
+            .collect();
+        let min: Vec<f64> = self.frames.iter().map(|frame| frame.min()).collect();
+        let max: Vec<f64> = self.frames.iter().map(|frame| frame.max()).collect();
+        let n = self.frames.len() as u64;
+        let pixel_scale: Vec<f64> = self
+            .frames
+            .first()
+            .and_then(|frame| frame.pixel_scale())
+            .into_iter()
+            .collect();
+        let mut arrays = vec![
+            (
+                "frames",
+                frames.as_slice(),
+                vec![n, rows as u64, cols as u64],
+            ),
+            ("opd min", min.as_slice(), vec![n]),
+            ("opd max", max.as_slice(), vec![n]),
+        ];
+        if !pixel_scale.is_empty() {
+            arrays.push(("pixel scale", pixel_scale.as_slice(), vec![1]));
+        }
+        write_npz(path, &arrays, Order::C)
+    }
+    /// Reads a cube from a npz file written with [OpdCube::to_npz]
+    ///
+    /// A `frames` array that is not 3D is read as 512x512 frames.
+    /// Returns [GlaoError::Dimensions] if the size of the `frames` array isn't a multiple of the frame size,
+    /// or [GlaoError::FrameCount] if the `opd min` or `opd max` arrays don't have one value per frame
+    pub fn from_npz<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = io::BufReader::new(File::open(path)?);
+        let mut zip = zip::ZipArchive::new(file)?;
+
+        let file = zip.by_name(&npz::file_name_from_array_name("frames"))?;
+        let reader = NpyFile::new(file)?;
+        let shape = reader.shape().to_vec();
+        let data = into_f64_vec(reader)?;
+        let (rows, cols) = match shape.as_slice() {
+            &[_, rows, cols] => (rows as usize, cols as usize),
+            _ => (512, 512),
+        };
+        if rows * cols == 0 || data.len() % (rows * cols) != 0 {
+            return Err(GlaoError::Dimensions(data.len(), rows, cols));
+        }
+        let n_frame = data.len() / (rows * cols);
+
+        let file = zip.by_name(&npz::file_name_from_array_name("opd min"))?;
+        let min = into_f64_vec(NpyFile::new(file)?)?;
+        let file = zip.by_name(&npz::file_name_from_array_name("opd max"))?;
+        let max = into_f64_vec(NpyFile::new(file)?)?;
+
+        let pixel_scale = match zip.by_name(&npz::file_name_from_array_name("pixel scale")) {
+            Ok(file) => Some(into_f64_vec(NpyFile::new(file)?)?[0]),
+            Err(_) => None,
+        };
+        if let Some(n) = [min.len(), max.len()].into_iter().find(|&n| n != n_frame) {
+            return Err(GlaoError::FrameCount(n_frame, n));
+        }
+
+        let frames = data
+            .chunks(rows * cols)
+            .zip(min.into_iter().zip(max))
+            .map(|(frame, (min, max))| {
+                let opd = OPD::new_with_dims(frame.to_vec(), rows, cols)?.with_min_max(min, max);
+                Ok(opd.with_optional_pixel_scale(pixel_scale))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(frames))
+    }
+    /// Applies `stat` to the valid values of each pixel across the frames
+    ///
+    /// Invalid pixels are set to NaN
+    fn temporal<F>(&self, stat: F) -> OPD
+    where
+        F: Fn(&[f64]) -> f64 + Sync,
+    {
+        let first = self.frames.first().expect("empty OPD cube");
+        let (rows, cols) = first.shape();
+        let data: Vec<f64> = (0..rows * cols)
+            .into_par_iter()
+            .map(|k| {
+                let values: Vec<f64> = self
+                    .frames
+                    .iter()
+                    .map(|frame| frame.map()[k])
+                    .filter(|x| !x.is_nan())
+                    .collect();
+                match self.validity {
+                    Validity::All if values.len() < self.frames.len() => f64::NAN,
+                    _ if values.is_empty() => f64::NAN,
+                    _ => stat(&values),
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, rows, cols).expect("OPD cube frame size mismatch");
+        opd.with_optional_pixel_scale(first.pixel_scale())
+    }
+    /// Returns the map of the per-pixel temporal mean
+    ///
+    /// # Panics
+    /// If the cube is empty
+    pub fn temporal_mean(&self) -> OPD {
+        self.temporal(|x| x.iter().sum::<f64>() / x.len() as f64)
+    }
+    /// Returns the map of the per-pixel temporal variance
+    ///
+    /// # Panics
+    /// If the cube is empty
+    pub fn temporal_var(&self) -> OPD {
+        self.temporal(|x| {
+            let n = x.len() as f64;
+            let mean = x.iter().sum::<f64>() / n;
+            x.iter().map(|x| x - mean).map(|x| x * x).sum::<f64>() / n
+        })
+    }
+    /// Returns the map of the per-pixel temporal root mean square
+    ///
+    /// # Panics
+    /// If the cube is empty
+    pub fn temporal_rms(&self) -> OPD {
+        self.temporal(|x| (x.iter().map(|x| x * x).sum::<f64>() / x.len() as f64).sqrt())
+    }
+    /// Returns the index of the frame with the largest whole pupil RMS
+    ///
+    /// # Panics
+    /// If the cube is empty
+    pub fn worst_time_frame(&self) -> usize {
+        self.frames
+            .iter()
+            .map(|frame| frame.rms())
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+            .expect("empty OPD cube")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temporal_mean_var() {
+        let frames = vec![
+            OPD::new_with_dims(vec![1., 2., f64::NAN, f64::NAN], 2, 2).unwrap(),
+            OPD::new_with_dims(vec![3., 2., 5., f64::NAN], 2, 2).unwrap(),
+        ];
+        let cube = OpdCube::new(frames);
+        assert_eq!(cube.frame(1).pixel(1, 0), 5.);
+
+        let mean = cube.temporal_mean();
+        assert_eq!(mean.shape(), (2, 2));
+        assert_eq!(&mean.map()[..2], &[2., 2.]);
+        assert!(mean.map()[2..].iter().all(|x| x.is_nan()));
+        let var = cube.temporal_var();
+        assert_eq!(&var.map()[..2], &[1., 0.]);
+
+        let cube = cube.with_validity(Validity::Any);
+        let mean = cube.temporal_mean();
+        assert_eq!(&mean.map()[..3], &[2., 2., 5.]);
+        assert!(mean.map()[3].is_nan());
+        assert_eq!(cube.temporal_var().map()[2], 0.);
+    }
+
+    #[test]
+    fn temporal_rms() {
+        let cube: OpdCube = (0..5)
+            .map(|i| {
+                let amplitude = if i == 3 { 10. } else { 1. };
+                OPD::new_with_dims(
+                    (0..16)
+                        .map(|k| amplitude * ((k * (i + 1)) as f64).sin())
+                        .collect(),
+                    4,
+                    4,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>()
+            .into();
+        assert_eq!(cube.worst_time_frame(), 3);
+        let rms = cube.temporal_rms();
+        let var = cube.temporal_var();
+        let mean = cube.temporal_mean();
+        rms.map()
+            .iter()
+            .zip(var.map().iter().zip(mean.map()))
+            .for_each(|(r, (v, m))| assert!((r * r - v - m * m).abs() < 1e-12));
+    }
+
+    #[test]
+    fn cube_npz() {
+        let cube = OpdCube::new(
+            (0..3)
+                .map(|i| {
+                    OPD::new_with_dims(
+                        (0..24)
+                            .map(|k| {
+                                if k % 7 == 0 {
+                                    f64::NAN
+                                } else {
+                                    ((k + i) as f64).sin() * 1e-6
+                                }
+                            })
+                            .collect(),
+                        4,
+                        6,
+                    )
+                    .unwrap()
+                    .with_pixel_scale(0.05)
+                })
+                .collect(),
+        );
+        let path = std::env::temp_dir().join("opd_cube.npz");
+        cube.to_npz(&path).unwrap();
+        let npz_cube = OpdCube::from_npz(&path).unwrap();
+        assert_eq!(npz_cube.len(), cube.len());
+        for (npz_frame, frame) in npz_cube.frames.iter().zip(&cube.frames) {
+            assert_eq!(npz_frame.shape(), frame.shape());
+            assert_eq!(npz_frame.pixel_scale(), frame.pixel_scale());
+            assert_eq!(
+                (npz_frame.min(), npz_frame.max()),
+                (frame.min(), frame.max())
+            );
+            assert_eq!(npz_frame.valid_mask(), frame.valid_mask());
+            assert_eq!(
+                npz_frame.no_nan_opd().collect::<Vec<_>>(),
+                frame.no_nan_opd().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn cube_npz_size_mismatch() {
+        let path = std::env::temp_dir().join("opd_cube_size_mismatch.npz");
+        let frames = vec![1e-9; 48];
+        write_npz(
+            &path,
+            &[
+                ("frames", frames.as_slice(), vec![2, 4, 6]),
+                ("opd min", &[1e-9], vec![1]),
+                ("opd max", &[1e-9], vec![1]),
+            ],
+            Order::C,
+        )
+        .unwrap();
+        assert!(matches!(
+            OpdCube::from_npz(&path),
+            Err(GlaoError::FrameCount(2, 1))
+        ));
+        write_npz(
+            &path,
+            &[
+                ("frames", frames.as_slice(), vec![48]),
+                ("opd min", &[1e-9], vec![1]),
+                ("opd max", &[1e-9], vec![1]),
+            ],
+            Order::C,
+        )
+        .unwrap();
+        assert!(matches!(
+            OpdCube::from_npz(&path),
+            Err(GlaoError::Dimensions(48, 512, 512))
+        ));
+    }
+}
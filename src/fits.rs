@@ -0,0 +1,167 @@
+use crate::{GlaoError, Result, OPD};
+use fitsio::{
+    hdu::HduInfo,
+    images::{ImageDescription, ImageType},
+    FitsFile,
+};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// Writes a sequence of OPD maps into a FITS cube
+///
+/// The cube is `N x rows x cols` with N the number of OPD maps,
+/// all the maps are expected to be sampled on the grid of the first one (512x512 by default).
+/// Pixels outside the exit pupil are set to NaN
+pub fn write_opd_cube_fits<P: AsRef<Path>>(opds: &[OPD], path: P) -> Result<()> {
+    let (rows, cols) = opds.first().map_or((512, 512), |opd| opd.shape());
+    let n_px = rows * cols;
+    let mut cube = vec![f64::NAN; opds.len() * n_px];
+    cube.par_chunks_mut(n_px)
+        .zip(opds)
+        .for_each(|(plane, opd)| {
+            plane.iter_mut().zip(opd.map()).for_each(|(p, &o)| *p = o);
+        });
+    let description = ImageDescription {
+        data_type: ImageType::Double,
+        dimensions: &[opds.len(), rows, cols],
+    };
+    let mut fptr = FitsFile::create(path.as_ref())
+        .with_custom_primary(&description)
+        .overwrite()
+        .open()?;
+    let hdu = fptr.primary_hdu()?;
+    hdu.write_image(&mut fptr, &cube)?;
+    Ok(())
+}
+
+impl OPD {
+    /// Reads a dome seeing OPD map from the primary HDU of a FITS file
+    ///
+    /// The image may be non-square but must have as many pixels as the 512x512 ASMS pupil.
+    /// NaN and `BLANK` pixels are set to NaN.
+    /// The OPD maximum and minimum are read from the `OPD_MAX` and `OPD_MIN` keywords,
+    /// if present, or are computed from the finite values of the map
+    pub fn from_fits<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut fptr = FitsFile::open(path.as_ref())?;
+        let hdu = fptr.primary_hdu()?;
+        let (rows, cols) = match &hdu.info {
+            HduInfo::ImageInfo { shape, .. } if shape.len() == 2 => (shape[0], shape[1]),
+            HduInfo::ImageInfo { shape, .. } => {
+                return Err(GlaoError::PupilSize(shape.iter().product()))
+            }
+            _ => return Err(GlaoError::PupilSize(0)),
+        };
+        if rows * cols != 512 * 512 {
+            return Err(GlaoError::PupilSize(rows * cols));
+        }
+        let mut data: Vec<f64> = hdu.read_image(&mut fptr)?;
+        if let Ok(blank) = hdu.read_key::<i64>(&mut fptr, "BLANK") {
+            let bscale = hdu.read_key::<f64>(&mut fptr, "BSCALE").unwrap_or(1f64);
+            let bzero = hdu.read_key::<f64>(&mut fptr, "BZERO").unwrap_or(0f64);
+            let blank = bzero + bscale * blank as f64;
+            data.iter_mut()
+                .filter(|x| **x == blank)
+                .for_each(|x| *x = f64::NAN);
+        }
+        let opd = OPD::new_with_dims(data, rows, cols)?;
+        match (
+            hdu.read_key::<f64>(&mut fptr, "OPD_MIN"),
+            hdu.read_key::<f64>(&mut fptr, "OPD_MAX"),
+        ) {
+            (Ok(min), Ok(max)) => Ok(opd.with_min_max(min, max)),
+            _ => Ok(opd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opd_cube_fits() {
+        let n_px = 512 * 512;
+        let opds: Vec<_> = (0..2)
+            .map(|k| {
+                OPD::new(
+                    (0..n_px)
+                        .map(|i| {
+                            if i % 3 == 0 {
+                                f64::NAN
+                            } else {
+                                (k * n_px + i) as f64 * 1e-9
+                            }
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        let path = std::env::temp_dir().join("opd_cube.fits");
+        write_opd_cube_fits(&opds, &path).unwrap();
+
+        let mut fptr = FitsFile::open(&path).unwrap();
+        let hdu = fptr.primary_hdu().unwrap();
+        let cube: Vec<f64> = hdu.read_image(&mut fptr).unwrap();
+        assert_eq!(cube.len(), 2 * n_px);
+        for (plane, opd) in cube.chunks(n_px).zip(&opds) {
+            plane
+                .iter()
+                .zip(opd.map())
+                .filter(|(_, o)| !o.is_nan())
+                .for_each(|(p, o)| assert_eq!(p, o));
+            assert_eq!(
+                plane.iter().filter(|p| p.is_nan()).count(),
+                opd.map().iter().filter(|o| o.is_nan()).count()
+            );
+        }
+    }
+
+    #[test]
+    fn opd_from_fits() {
+        let (rows, cols) = (256, 1024);
+        let data: Vec<_> = (0..rows * cols)
+            .map(|i| {
+                if i % 5 == 0 {
+                    f64::NAN
+                } else {
+                    i as f64 * 1e-12
+                }
+            })
+            .collect();
+        let write = |path: &Path, keys: bool| {
+            let description = ImageDescription {
+                data_type: ImageType::Double,
+                dimensions: &[rows, cols],
+            };
+            let mut fptr = FitsFile::create(path)
+                .with_custom_primary(&description)
+                .overwrite()
+                .open()
+                .unwrap();
+            let hdu = fptr.primary_hdu().unwrap();
+            hdu.write_image(&mut fptr, &data).unwrap();
+            if keys {
+                hdu.write_key(&mut fptr, "OPD_MIN", -1f64).unwrap();
+                hdu.write_key(&mut fptr, "OPD_MAX", 1f64).unwrap();
+            }
+        };
+
+        let path = std::env::temp_dir().join("opd.fits");
+        write(&path, false);
+        let opd = OPD::from_fits(&path).unwrap();
+        assert_eq!(opd.shape(), (rows, cols));
+        assert_eq!(
+            opd.valid_mask(),
+            data.iter().map(|x| !x.is_nan()).collect::<Vec<_>>()
+        );
+        assert!(opd.no_nan_opd().eq(data.iter().filter(|x| !x.is_nan())));
+        assert_eq!(
+            (opd.min(), opd.max()),
+            (1e-12, (rows * cols - 1) as f64 * 1e-12)
+        );
+
+        write(&path, true);
+        let opd = OPD::from_fits(&path).unwrap();
+        assert_eq!((opd.min(), opd.max()), (-1., 1.));
+    }
+}
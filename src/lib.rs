@@ -5,11 +5,16 @@ ASM fitting model and dome seeing OPD data processing
 
  */
 
+use bincode::Options;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
+    fs,
+    io::Write,
     iter::Once,
     ops::{Sub, SubAssign},
+    path::Path,
 };
 use thiserror::Error;
 
@@ -17,7 +22,14 @@ pub mod asm;
 #[doc(inline)]
 pub use asm::ASM;
 mod opd;
-pub use opd::OPD;
+pub use opd::{mean_map, Window, OPD};
+mod cube;
+pub use cube::{OpdCube, Validity};
+#[cfg(feature = "fits")]
+mod fits;
+pub mod zernike;
+#[cfg(feature = "fits")]
+pub use fits::write_opd_cube_fits;
 
 #[derive(Debug, Error)]
 pub enum GlaoError {
@@ -33,11 +45,34 @@ pub enum GlaoError {
     Zip(#[from] zip::result::ZipError),
     #[error("npyz variable read failed")]
     Npyz(#[from] npyz::DTypeError),
+    #[error("unsupported npy data type {0}")]
+    DType(String),
     #[error("pseudo-inverse failed with {0}")]
     PseudoInverse(String),
+    #[error("data size {0} doesn't match a {1}x{2} grid")]
+    Dimensions(usize, usize, usize),
+    #[error("expected {0} frames, found {1}")]
+    FrameCount(usize, usize),
+    #[error("{0} mode values don't match {1} modes over {2} pupil points")]
+    ModeCount(usize, usize, usize),
+    #[error("expected {0} modes, found {1}")]
+    NMode(usize, usize),
+    #[error("mode index {0} out of range for {1} modes")]
+    ModeIndex(usize, usize),
+    #[cfg(feature = "fits")]
+    #[error("FITS file failed")]
+    Fits(#[from] fitsio::errors::Error),
+    #[error("OPD size {0} doesn't match the 512x512 ASMS pupil")]
+    PupilSize(usize),
+    #[cfg(feature = "async")]
+    #[error("async task failed")]
+    Join(#[from] tokio::task::JoinError),
 }
 pub type Result<T> = std::result::Result<T, GlaoError>;
 
+/// Number of ASM segments
+pub const N_SEGMENT: usize = 7;
+
 /// A single OPD data processing result
 #[derive(Serialize, Deserialize)]
 pub struct OpdRecord {
@@ -51,11 +86,230 @@ pub struct OpdRecord {
     pub modal_coefficients: Vec<f64>,
     /// segment area to exit pupil area ratios
     pub ratios: Vec<f64>,
+    /// OPD segment variance
+    #[serde(default)]
+    pub segment_var: Vec<f64>,
+}
+impl OpdRecord {
+    /// Returns the OPD RMS
+    pub fn rms(&self) -> f64 {
+        self.var.sqrt()
+    }
+    /// Returns the OPD segment root sum square
+    pub fn segment_rms(&self) -> Vec<f64> {
+        self.segment_sum_square.iter().map(|x| x.sqrt()).collect()
+    }
+}
+/// [OpdRecord] layout prior to the addition of [OpdRecord::segment_var]
+#[derive(Deserialize)]
+struct LegacyOpdRecord {
+    file: String,
+    var: f64,
+    segment_sum_square: Vec<f64>,
+    modal_coefficients: Vec<f64>,
+    ratios: Vec<f64>,
+}
+impl From<LegacyOpdRecord> for OpdRecord {
+    fn from(record: LegacyOpdRecord) -> Self {
+        Self {
+            file: record.file,
+            var: record.var,
+            segment_sum_square: record.segment_sum_square,
+            modal_coefficients: record.modal_coefficients,
+            ratios: record.ratios,
+            segment_var: Vec::new(),
+        }
+    }
+}
+/// Loads [OpdRecord]s from a [bincode] file
+///
+/// Files written before the addition of [OpdRecord::segment_var] are also supported,
+/// in which case `segment_var` is left empty
+pub fn records_from_bin<P: AsRef<Path>>(path: P) -> Result<Vec<OpdRecord>> {
+    let bytes = fs::read(path)?;
+    let options = bincode::DefaultOptions::new().with_fixint_encoding();
+    match options.deserialize::<Vec<OpdRecord>>(&bytes) {
+        Ok(records) => Ok(records),
+        Err(_) => Ok(options
+            .deserialize::<Vec<LegacyOpdRecord>>(&bytes)?
+            .into_iter()
+            .map(|record| record.into())
+            .collect()),
+    }
+}
+/// Writes [OpdRecord]s in CSV format, one row per record
+///
+/// The columns are the file name, the variance, the segment sum squares and the segment area ratios
+pub fn records_to_csv<W: Write>(records: &[OpdRecord], w: W) -> Result<()> {
+    records_to_csv_with_modes(records, 0, w)
+}
+/// Writes [OpdRecord]s in CSV format, one row per record, with the first `n_mode` modal coefficients of each segment
+///
+/// The modal coefficients columns follow the columns of [records_to_csv]
+pub fn records_to_csv_with_modes<W: Write>(
+    records: &[OpdRecord],
+    n_mode: usize,
+    mut w: W,
+) -> Result<()> {
+    let n_segment = records
+        .first()
+        .map_or(0, |record| record.segment_sum_square.len());
+    let mut header = vec!["file".to_string(), "var".to_string()];
+    header.extend((1..=n_segment).map(|sid| format!("segment_sum_square_{sid}")));
+    header.extend((1..=n_segment).map(|sid| format!("ratio_{sid}")));
+    for sid in 1..=n_segment {
+        header.extend((0..n_mode).map(|k| format!("b_{sid}_{k}")));
+    }
+    writeln!(w, "{}", header.join(","))?;
+    for record in records {
+        let mut row = vec![
+            format!("\"{}\"", record.file.replace('"', "\"\"")),
+            record.var.to_string(),
+        ];
+        row.extend(record.segment_sum_square.iter().map(|x| x.to_string()));
+        row.extend(record.ratios.iter().map(|x| x.to_string()));
+        let n = record
+            .modal_coefficients
+            .len()
+            .checked_div(n_segment)
+            .unwrap_or(0);
+        if n_mode > 0 && n > 0 {
+            for b in record.modal_coefficients.chunks(n) {
+                row.extend(b.iter().take(n_mode).map(|x| x.to_string()));
+            }
+        }
+        writeln!(w, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+/// Returns the whole pupil WFE from the segment WFE root sum squares weighted by the segment area ratios
+///
+/// The whole pupil WFE is `sqrt(Σ rss² ratio)`
+pub fn combine_segment_wfe(segment_rss: &[f64], ratios: &[f64]) -> f64 {
+    segment_rss
+        .iter()
+        .zip(ratios)
+        .map(|(r, c)| r * r * c)
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Returns the residual OPD RMS of each OPD file after removing the ASMS shape
+///
+/// The ASMS modal coefficients are either the least square fit ([ASMS::least_square])
+/// or the projection ([ASMS::project]) of each OPD.
+/// The series follows the order of `files`
+pub fn residual_timeseries<P: AsRef<Path>>(
+    asms: &mut Vec<ASM>,
+    files: &[P],
+    use_lsq: bool,
+) -> Result<Vec<f64>> {
+    files
+        .iter()
+        .map(|file| {
+            let opd: OPD = OPD::from_npz(file)?;
+            if use_lsq {
+                asms.least_square(&opd)?;
+            } else {
+                asms.project(&opd)?;
+            }
+            Ok((&opd - &*asms).rms())
+        })
+        .collect()
+}
+
+/// Analysis of a dome seeing OPD map
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpdAnalysis {
+    /// OPD RMS over the ASMS pupil
+    pub opd_rms: f64,
+    /// OPD root sum square on each segment
+    pub segment_rms: Vec<f64>,
+    /// Residual OPD RMS after removing the ASMS shape
+    pub residual_rms: f64,
+    /// Strehl ratio of the residual OPD
+    pub strehl: f64,
+    /// Modal coefficients of each segment
+    pub coefficients: Vec<Vec<f64>>,
+}
+/// Analyzes a dome seeing OPD map
+///
+/// The OPD is read from the npz file, restricted to the ASMS pupil and its mean is removed.
+/// The OPD is then projected on the ASMS modes and the ASMS shape is removed from the OPD.
+/// The Strehl ratio of the residual OPD is given by [OPD::strehl] at `wavelength`
+pub fn analyze_opd<P: AsRef<Path>>(
+    path: P,
+    asms: &mut Vec<ASM>,
+    wavelength: f64,
+) -> Result<OpdAnalysis> {
+    let mut opd: OPD = OPD::from_npz(path)?;
+    opd.mask_with(&asms.mask()).zero_mean();
+    asms.project(&opd)?;
+    let residuals = &opd - &*asms;
+    Ok(OpdAnalysis {
+        opd_rms: opd.std(),
+        segment_rms: asms.iter().map(|asm| opd.masked_rss(asm.mask())).collect(),
+        residual_rms: residuals.std(),
+        strehl: residuals.strehl(wavelength),
+        coefficients: asms.iter().map(|asm| asm.coefficients().to_vec()).collect(),
+    })
+}
+
+/// Summary of the OPD statistics of a CFD case
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpdSummary {
+    /// CFD case
+    pub cfd_case: String,
+    /// Mean of the OPD standard deviations
+    pub mean_std: f64,
+    /// Mean of the OPD segment root sum squares
+    pub mean_segment_rss: Vec<f64>,
+}
+/// Returns the percentile `q` (within [0,1]) of sorted values, interpolating linearly between values
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let x = q.clamp(0., 1.) * (sorted.len() - 1) as f64;
+    let (i, f) = (x.floor() as usize, x.fract());
+    if f > 0. {
+        sorted[i] + f * (sorted[i + 1] - sorted[i])
+    } else {
+        sorted[i]
+    }
+}
+/// Returns the percentiles `p` (within [0,100]) of the CFD cases [OpdSummary::mean_std]
+pub fn case_wfe_percentiles(summaries: &[OpdSummary], p: &[f64]) -> Vec<f64> {
+    let mut mean_std: Vec<_> = summaries.iter().map(|s| s.mean_std).collect();
+    mean_std.sort_by(|a, b| a.total_cmp(b));
+    p.iter().map(|p| percentile(&mean_std, p * 1e-2)).collect()
+}
+
+/// Returns the number of segments and the number of modes per segment of the `records`
+///
+/// Returns `None` if there is no record, no segment or no mode,
+/// or if any record doesn't have `n_segment x n_mode` modal coefficients
+fn modal_dims(records: &[OpdRecord]) -> Option<(usize, usize)> {
+    let first = records.first()?;
+    let n_segment = first.segment_sum_square.len();
+    let n_mode = first.modal_coefficients.len().checked_div(n_segment)?;
+    (n_mode > 0
+        && records.iter().all(|record| {
+            record.segment_sum_square.len() == n_segment
+                && record.modal_coefficients.len() == n_segment * n_mode
+        }))
+    .then_some((n_segment, n_mode))
 }
 
 pub trait OpdStats {
     fn mean_var(&self) -> f64;
     fn mean_segment_sum_square(&self) -> Vec<f64>;
+    /// Returns the mean over the records of the segment residual sum squares
+    /// after removing the modes one after the other
+    ///
+    /// The vector is empty if the records have no modal coefficients
+    /// or if they don't all have the same number of segments and modes
     fn mean_segment_residual_sum_square(&self) -> Vec<f64>;
     fn mean_std(&self) -> f64 {
         <Self as OpdStats>::mean_var(self).sqrt()
@@ -72,17 +326,81 @@ pub trait OpdStats {
             .map(|x| x.sqrt())
             .collect()
     }
+    /// Returns the mean over the records of the modal coefficients squared
+    ///
+    /// The vector is empty if the records have no modal coefficients
+    /// or if they don't all have the same number of segments and modes
     fn mean_modal_coefs_square(&self) -> Vec<f64>;
+    /// Bootstraps the mean variance
+    ///
+    /// The records are resampled with replacement `n_resamples` times with a random number generator
+    /// seeded with `seed`.
+    /// Returns the bootstrap mean of the mean variance and the 2.5 and 97.5 percentiles
+    fn bootstrap_mean_var(&self, n_resamples: usize, seed: u64) -> (f64, f64, f64);
+    /// Returns the percentile `p` (clamped to [0,1]) of the variances
+    ///
+    /// The percentile is interpolated linearly between the sorted variances,
+    /// it is NaN if there is no variance
+    fn percentile_var(&self, p: f64) -> f64;
+    /// Returns the median of the variances
+    fn median_var(&self) -> f64 {
+        <Self as OpdStats>::percentile_var(self, 0.5)
+    }
+    /// Returns the `n_mode x n_mode` covariance matrix of the modal coefficients of a `segment`
+    ///
+    /// The segment index starts at 0.
+    /// The matrix is empty if `segment` is out of range, if there are less than 2 records,
+    /// or if the records don't all have the same number of segments and modes
+    fn modal_covariance(&self, segment: usize) -> nalgebra::DMatrix<f64>;
 }
 impl OpdStats for Vec<OpdRecord> {
     fn mean_var(&self) -> f64 {
         self.iter().map(|record| record.var).sum::<f64>() / self.len() as f64
     }
+    fn bootstrap_mean_var(&self, n_resamples: usize, seed: u64) -> (f64, f64, f64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let n = self.len();
+        let mut mean_vars: Vec<_> = (0..n_resamples)
+            .map(|_| (0..n).map(|_| self[rng.gen_range(0..n)].var).sum::<f64>() / n as f64)
+            .collect();
+        mean_vars.sort_by(|a, b| a.total_cmp(b));
+        (
+            mean_vars.iter().sum::<f64>() / n_resamples as f64,
+            percentile(&mean_vars, 0.025),
+            percentile(&mean_vars, 0.975),
+        )
+    }
+    fn modal_covariance(&self, segment: usize) -> nalgebra::DMatrix<f64> {
+        let n = self.len();
+        let (n_segment, n_mode) = modal_dims(self).unwrap_or((0, 0));
+        if segment >= n_segment || n < 2 {
+            return nalgebra::DMatrix::zeros(0, 0);
+        }
+        let b: Vec<_> = self
+            .iter()
+            .flat_map(|record| &record.modal_coefficients[segment * n_mode..(segment + 1) * n_mode])
+            .cloned()
+            .collect();
+        let mut x = nalgebra::DMatrix::from_row_slice(n, n_mode, &b);
+        for mut column in x.column_iter_mut() {
+            let mean = column.mean();
+            column.add_scalar_mut(-mean);
+        }
+        x.tr_mul(&x) / (n - 1) as f64
+    }
+    fn percentile_var(&self, p: f64) -> f64 {
+        let mut vars: Vec<_> = self.iter().map(|record| record.var).collect();
+        vars.sort_by(|a, b| a.total_cmp(b));
+        percentile(&vars, p)
+    }
     fn mean_segment_sum_square(&self) -> Vec<f64> {
         let n = self.len() as f64;
+        let n_segment = self
+            .first()
+            .map_or(0, |record| record.segment_sum_square.len());
         self.iter()
             .map(|record| &record.segment_sum_square)
-            .fold(vec![0f64; 7], |mut a, sss| {
+            .fold(vec![0f64; n_segment], |mut a, sss| {
                 a.iter_mut().zip(sss).for_each(|(a, s)| *a += s);
                 a
             })
@@ -93,10 +411,12 @@ impl OpdStats for Vec<OpdRecord> {
 
     fn mean_modal_coefs_square(&self) -> Vec<f64> {
         let n = self.len() as f64;
-        let n_mode = 500;
+        let Some((n_segment, n_mode)) = modal_dims(self) else {
+            return Vec::new();
+        };
         self.iter()
             .map(|record| &record.modal_coefficients)
-            .fold(vec![0f64; 7 * n_mode], |mut a, b| {
+            .fold(vec![0f64; n_segment * n_mode], |mut a, b| {
                 a.iter_mut().zip(b).for_each(|(a, b)| *a += b * b);
                 a
             })
@@ -106,7 +426,9 @@ impl OpdStats for Vec<OpdRecord> {
     }
     fn mean_segment_residual_sum_square(&self) -> Vec<f64> {
         let n = self.len() as f64;
-        let n_mode = 500;
+        let Some((n_segment, n_mode)) = modal_dims(self) else {
+            return Vec::new();
+        };
         self.iter()
             .map(|record| {
                 record
@@ -126,7 +448,7 @@ impl OpdStats for Vec<OpdRecord> {
                     })
                     .collect::<Vec<f64>>()
             })
-            .fold(vec![0f64; 7 * n_mode], |mut a, sss| {
+            .fold(vec![0f64; n_segment * n_mode], |mut a, sss| {
                 a.iter_mut().zip(sss).for_each(|(a, s)| *a += s);
                 a
             })
@@ -142,6 +464,28 @@ pub trait ASMS {
     /// The modes are loaded from [bincode] data files in the `gerpy` directory.
     /// 500 modes are expected.
     fn from_bins() -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::from_bins_n(N_SEGMENT)
+    }
+    /// Loads the Karhunen-Loeve modes of the first `n` segments
+    fn from_bins_n(n: usize) -> Result<Self>
+    where
+        Self: Sized;
+    /// Loads segment Karhunen-Loeve modes, calling `f` with the segment id as each segment is loaded
+    ///
+    /// The segments are loaded in parallel, so `f` is called from different threads
+    /// and in no particular order
+    fn from_bins_with_progress<F: Fn(usize) + Sync>(f: F) -> Result<Self>
+    where
+        Self: Sized;
+    /// Loads the 7 segments asynchronously
+    ///
+    /// The segments are loaded concurrently with [ASM::from_bin_async]
+    /// and must be awaited within a tokio runtime
+    #[cfg(feature = "async")]
+    fn from_bins_async() -> impl std::future::Future<Output = Result<Self>> + Send
     where
         Self: Sized;
     /// Return a mask for the ASMS
@@ -149,28 +493,107 @@ pub trait ASMS {
     /// Returns the mirror shape
     ///
     /// The shape is sampled on a 512x512 regular grid
-    /// Pixel outside the mirror footprint are set to NaN
+    /// Pixel outside the mirror footprint are set to NaN.
+    /// The segment shapes are computed in parallel and merged assuming that the segment masks are disjoint
     fn mirror_shape(&self, idx: Option<impl Iterator<Item = usize> + Clone>) -> OPD;
     /// Substracts the mirror shape from the opd
     ///
     /// The map is sampled on a 512x512 regular grid.
     /// Pixel outside the mirror footprint are set to NaN
     fn mirror_shape_sub(&self, opd: &mut OPD, idx: Option<impl Iterator<Item = usize> + Clone>);
+    /// Returns the mirror shape from the modal `coefficients` of all the segments
+    ///
+    /// The coefficients are concatenated segment after segment, as in [OpdRecord::modal_coefficients].
+    /// The shape is sampled on a 512x512 regular grid,
+    /// pixel outside the mirror footprint are set to NaN.
+    ///
+    /// Returns [GlaoError::NMode] if the number of coefficients doesn't match the number of modes
+    fn reconstruct(&self, coefficients: &[f64]) -> Result<OPD>;
+    /// Returns the RMS of the [ASMS::mirror_shape] steps across the segment boundaries
+    ///
+    /// The steps are the differences between pixels that are adjacent, either along a row or a column
+    /// of the 512x512 grid, and that belong to the masks of different segments.
+    /// Returns 0 if there is no such pair of pixels
+    fn edge_discontinuity(&self) -> f64;
     /// Projects `opd` on all the modes
+    ///
+    /// The projection is computed with [ASM::project_gemv]
     fn project(&mut self, opd: &OPD) -> Result<&mut Self>;
     fn least_square(&mut self, opd: &OPD) -> Result<&mut Self>;
-    fn project_out(&self, opd: &OPD) -> Vec<f64>;
-    fn least_square_out(&self, opd: &OPD) -> Vec<f64>;
+    /// Fits jointly the modes of all the segments to `opd`
+    ///
+    /// The fit solves the normal equations of the design matrix made of the modes of all the segments
+    /// over the union of the segment masks, the modes of segments with overlapping masks are coupled
+    /// through the shared pixels.
+    /// For disjoint masks, the fit is the same as [ASMS::least_square].
+    /// The coefficients of each segment are set from the joint solution
+    fn global_least_square(&mut self, opd: &OPD) -> Result<&mut Self>;
+    /// Projects `opd` on all the modes and returns the coefficients of all the segments
+    fn project_out(&self, opd: &OPD) -> Result<Vec<f64>>;
+    /// Projects `opd` on all the modes and writes the coefficients of all the segments into `out`
+    ///
+    /// `out` is cleared and its capacity reused across calls
+    fn project_out_into(&self, opd: &OPD, out: &mut Vec<f64>) -> Result<()>;
+    /// Projects each of the `opds` on all the modes
+    ///
+    /// Returns the coefficients of all the segments for each OPD,
+    /// the projections are batched per segment into a single matrix product
+    fn project_out_batch(&self, opds: &[&OPD]) -> Result<Vec<Vec<f64>>>;
+    /// Fits all the modes to `opd` and returns the coefficients of all the segments
+    fn least_square_out(&self, opd: &OPD) -> Result<Vec<f64>>;
     /// Segment area to exit pupil area ratios
+    ///
+    /// The segment areas are given by the number of samples of the modes [ASM::n_point]
     fn area_ratios(&self) -> Vec<f64>;
+    /// Segment area to exit pupil area ratios from the segment masks
+    ///
+    /// The segment areas are given by the number of points within the masks [ASM::n_in_mask],
+    /// that differs from [ASMS::area_ratios] for segments with modes not sampled on the whole mask
+    fn mask_area_ratios(&self) -> Vec<f64>;
+    /// Returns the sum of the segment mask areas for a given `pixel_scale`
+    fn total_area(&self, pixel_scale: f64) -> f64;
+    /// Returns the residual `opd` after removing the first `n` modes of each segment
+    ///
+    /// The modal coefficients are derived from the projection of `opd` on the modes
+    fn residual_first_n(&mut self, opd: &OPD, n: usize) -> Result<OPD>;
+    /// Projects `opd` on the modes of each segment given by the indices `idx`
+    ///
+    /// The coefficients of the other modes are set to zero
+    fn project_subset(
+        &mut self,
+        opd: &OPD,
+        idx: impl Iterator<Item = usize> + Clone,
+    ) -> Result<&mut Self>;
+    /// Returns the fractional contribution of each segment to the `opd` variance
+    ///
+    /// The contribution of a segment is its OPD variance weighted by its area ratio,
+    /// the contributions are normalized to a unit sum
+    fn variance_contributions(&self, opd: &OPD) -> Vec<f64>;
 }
 impl ASMS for Vec<ASM> {
-    fn from_bins() -> Result<Self> {
-        (1..=7)
+    fn from_bins_n(n: usize) -> Result<Self> {
+        (1..=n)
             .into_par_iter()
             .map(|sid| ASM::from_bin(sid))
             .collect()
     }
+    fn from_bins_with_progress<F: Fn(usize) + Sync>(f: F) -> Result<Self> {
+        (1..=N_SEGMENT)
+            .into_par_iter()
+            .map(|sid| ASM::from_bin(sid).inspect(|_| f(sid)))
+            .collect()
+    }
+    #[cfg(feature = "async")]
+    async fn from_bins_async() -> Result<Self> {
+        let handles: Vec<_> = (1..=N_SEGMENT)
+            .map(|sid| tokio::spawn(ASM::from_bin_async(sid)))
+            .collect();
+        let mut asms = Vec::with_capacity(handles.len());
+        for handle in handles {
+            asms.push(handle.await??);
+        }
+        Ok(asms)
+    }
     fn mask(&self) -> Vec<bool> {
         self.iter().fold(vec![false; 512 * 512], |mut a, asm| {
             a.iter_mut()
@@ -180,9 +603,14 @@ impl ASMS for Vec<ASM> {
         })
     }
     fn mirror_shape(&self, idx: Option<impl Iterator<Item = usize> + Clone>) -> OPD {
+        // the mode indices are collected to be shared across threads
+        let idx: Option<Vec<usize>> = idx.map(|idx| idx.collect());
+        let segment_shapes: Vec<_> = self
+            .par_iter()
+            .map(|asm| asm.shape(idx.as_ref().map(|idx| idx.iter().cloned())))
+            .collect();
         let mut shape = vec![f64::NAN; 512 * 512];
-        for asm in self {
-            let segment_shape = asm.shape(idx.clone());
+        for (asm, segment_shape) in self.iter().zip(segment_shapes) {
             asm.masked_replace(&mut shape, segment_shape);
         }
         OPD::new(shape)
@@ -199,10 +627,56 @@ impl ASMS for Vec<ASM> {
             asm.masked_sub(opd_map, segment_shape);
         }
     }
+    fn reconstruct(&self, coefficients: &[f64]) -> Result<OPD> {
+        let n_mode: usize = self.iter().map(|asm| asm.n_mode()).sum();
+        if coefficients.len() != n_mode {
+            return Err(GlaoError::NMode(n_mode, coefficients.len()));
+        }
+        let mut shape = vec![f64::NAN; 512 * 512];
+        let mut coefficients = coefficients;
+        for asm in self {
+            let (segment_coefficients, tail) = coefficients.split_at(asm.n_mode());
+            asm.masked_replace(&mut shape, asm.synthesize(segment_coefficients));
+            coefficients = tail;
+        }
+        Ok(OPD::new(shape))
+    }
+    fn edge_discontinuity(&self) -> f64 {
+        let side = 512;
+        let mut segment_id = vec![None; side * side];
+        for (sid, asm) in self.iter().enumerate() {
+            segment_id
+                .iter_mut()
+                .zip(asm.mask())
+                .filter(|(_, &m)| m)
+                .for_each(|(id, _)| *id = Some(sid));
+        }
+        let shape = self.mirror_shape(Option::<Once<usize>>::None);
+        let shape = shape.map();
+        let (n, ss) = (0..side * side)
+            .flat_map(|k| {
+                let right = (k % side + 1 < side).then_some(k + 1);
+                let below = (k + side < side * side).then_some(k + side);
+                right.into_iter().chain(below).map(move |j| (k, j))
+            })
+            .filter(|&(k, j)| match (segment_id[k], segment_id[j]) {
+                (Some(a), Some(b)) => a != b,
+                _ => false,
+            })
+            .fold((0usize, 0f64), |(n, ss), (k, j)| {
+                let step = shape[k] - shape[j];
+                (n + 1, ss + step * step)
+            });
+        if n > 0 {
+            (ss / n as f64).sqrt()
+        } else {
+            0f64
+        }
+    }
     fn project(&mut self, opd: &OPD) -> Result<&mut Self> {
         let opd_map = opd.map();
         self.par_iter_mut()
-            .map(|asm| asm.project(opd_map))
+            .map(|asm| asm.project_gemv(opd_map))
             .collect::<Result<Vec<_>>>()?;
         Ok(self)
     }
@@ -213,17 +687,133 @@ impl ASMS for Vec<ASM> {
             .collect::<Result<Vec<_>>>()?;
         Ok(self)
     }
-    fn project_out(&self, opd: &OPD) -> Vec<f64> {
+    fn global_least_square(&mut self, opd: &OPD) -> Result<&mut Self> {
         let opd_map = opd.map();
-        self.par_iter()
-            .flat_map(|asm| asm.project_out(opd_map).unwrap())
-            .collect::<Vec<_>>()
+        // grid indices of the mode samples
+        let pixels = self
+            .iter()
+            .map(|asm| {
+                let pixels: Vec<usize> = asm
+                    .mask()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &m)| m)
+                    .map(|(k, _)| k)
+                    .collect();
+                if pixels.len() == asm.n_point() && opd_map.len() == asm.mask().len() {
+                    Ok(pixels)
+                } else {
+                    Err(GlaoError::Projection)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let modes: Vec<_> = self
+            .iter()
+            .map(|asm| nalgebra::DMatrixSlice::from_slice(asm.modes(), asm.n_point(), asm.n_mode()))
+            .collect();
+        let offsets: Vec<usize> = self
+            .iter()
+            .scan(0, |offset, asm| {
+                let o = *offset;
+                *offset += asm.n_mode();
+                Some(o)
+            })
+            .collect();
+        let n_mode: usize = self.iter().map(|asm| asm.n_mode()).sum();
+
+        let mut normal = nalgebra::DMatrix::<f64>::zeros(n_mode, n_mode);
+        let mut rhs = nalgebra::DVector::<f64>::zeros(n_mode);
+        for (i, (modes_i, pixels_i)) in modes.iter().zip(&pixels).enumerate() {
+            let (o_i, n_i) = (offsets[i], modes_i.ncols());
+            let opd_i = nalgebra::DVector::from_iterator(
+                pixels_i.len(),
+                pixels_i.iter().map(|&k| opd_map[k]),
+            );
+            rhs.rows_mut(o_i, n_i).copy_from(&modes_i.tr_mul(&opd_i));
+            normal
+                .slice_mut((o_i, o_i), (n_i, n_i))
+                .copy_from(&modes_i.tr_mul(modes_i));
+            for (j, (modes_j, pixels_j)) in modes.iter().zip(&pixels).enumerate().take(i) {
+                // samples of segments i and j on the same pixels
+                let (mut a, mut b) = (0, 0);
+                let (mut shared_i, mut shared_j) = (Vec::new(), Vec::new());
+                while a < pixels_i.len() && b < pixels_j.len() {
+                    match pixels_i[a].cmp(&pixels_j[b]) {
+                        std::cmp::Ordering::Less => a += 1,
+                        std::cmp::Ordering::Greater => b += 1,
+                        std::cmp::Ordering::Equal => {
+                            shared_i.push(a);
+                            shared_j.push(b);
+                            a += 1;
+                            b += 1;
+                        }
+                    }
+                }
+                if shared_i.is_empty() {
+                    continue;
+                }
+                let (o_j, n_j) = (offsets[j], modes_j.ncols());
+                let block = modes_i
+                    .select_rows(&shared_i)
+                    .tr_mul(&modes_j.select_rows(&shared_j));
+                normal.slice_mut((o_i, o_j), (n_i, n_j)).copy_from(&block);
+                normal
+                    .slice_mut((o_j, o_i), (n_j, n_i))
+                    .copy_from(&block.transpose());
+            }
+        }
+
+        let coefficients = normal
+            .cholesky()
+            .map(|chol| chol.solve(&rhs))
+            .ok_or_else(|| GlaoError::PseudoInverse("singular global normal matrix".into()))?;
+        for (asm, o) in self.iter_mut().zip(offsets) {
+            let n = asm.n_mode();
+            asm.set_coefficients(coefficients.as_slice()[o..o + n].to_vec());
+        }
+        Ok(self)
+    }
+    fn project_out(&self, opd: &OPD) -> Result<Vec<f64>> {
+        let opd_map = opd.map();
+        Ok(self
+            .par_iter()
+            .map(|asm| asm.project_out(opd_map))
+            .collect::<Result<Vec<_>>>()?
+            .concat())
+    }
+    fn project_out_into(&self, opd: &OPD, out: &mut Vec<f64>) -> Result<()> {
+        let opd_map = opd.map();
+        out.clear();
+        out.resize(self.iter().map(|asm| asm.n_mode()).sum(), 0f64);
+        let mut remaining = out.as_mut_slice();
+        for asm in self.iter() {
+            let (b, tail) = remaining.split_at_mut(asm.n_mode());
+            asm.project_out_into(opd_map, b)?;
+            remaining = tail;
+        }
+        Ok(())
+    }
+    fn project_out_batch(&self, opds: &[&OPD]) -> Result<Vec<Vec<f64>>> {
+        let opd_maps: Vec<_> = opds.iter().map(|opd| opd.map()).collect();
+        let segments_b = self
+            .par_iter()
+            .map(|asm| asm.project_out_batch(&opd_maps))
+            .collect::<Result<Vec<_>>>()?;
+        let mut b = vec![Vec::new(); opds.len()];
+        for segment_b in segments_b {
+            b.iter_mut()
+                .zip(segment_b)
+                .for_each(|(b, segment_b)| b.extend(segment_b));
+        }
+        Ok(b)
     }
-    fn least_square_out(&self, opd: &OPD) -> Vec<f64> {
+    fn least_square_out(&self, opd: &OPD) -> Result<Vec<f64>> {
         let opd_map = opd.map();
-        self.par_iter()
-            .flat_map(|asm| asm.least_square_out(opd_map).unwrap())
-            .collect::<Vec<_>>()
+        Ok(self
+            .par_iter()
+            .map(|asm| asm.least_square_out(opd_map))
+            .collect::<Result<Vec<_>>>()?
+            .concat())
     }
     fn area_ratios(&self) -> Vec<f64> {
         let n_points: Vec<_> = self.iter().map(|asm| asm.n_point()).collect();
@@ -233,6 +823,46 @@ impl ASMS for Vec<ASM> {
             .map(|x| x as f64 / nn_points as f64)
             .collect()
     }
+    fn mask_area_ratios(&self) -> Vec<f64> {
+        let n_in_masks: Vec<_> = self.iter().map(|asm| asm.n_in_mask()).collect();
+        let nn_in_masks: usize = n_in_masks.iter().sum();
+        n_in_masks
+            .into_iter()
+            .map(|x| x as f64 / nn_in_masks as f64)
+            .collect()
+    }
+    fn total_area(&self, pixel_scale: f64) -> f64 {
+        self.iter().map(|asm| asm.mask_area(pixel_scale)).sum()
+    }
+    fn residual_first_n(&mut self, opd: &OPD, n: usize) -> Result<OPD> {
+        let opd_map = opd.map();
+        self.par_iter_mut()
+            .map(|asm| asm.project_first_n(opd_map, n))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(opd - &*self)
+    }
+    fn project_subset(
+        &mut self,
+        opd: &OPD,
+        idx: impl Iterator<Item = usize> + Clone,
+    ) -> Result<&mut Self> {
+        let opd_map = opd.map();
+        // the mode indices are collected to be shared across threads
+        let idx: Vec<_> = idx.collect();
+        self.par_iter_mut()
+            .map(|asm| asm.project_subset(opd_map, idx.iter().cloned()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self)
+    }
+    fn variance_contributions(&self, opd: &OPD) -> Vec<f64> {
+        let weighted_vars: Vec<_> = self
+            .iter()
+            .zip(self.area_ratios())
+            .map(|(asm, ratio)| opd.masked_var(asm.mask()) * ratio)
+            .collect();
+        let total: f64 = weighted_vars.iter().sum();
+        weighted_vars.into_iter().map(|x| x / total).collect()
+    }
 }
 
 impl<T> SubAssign<&T> for OPD
@@ -256,3 +886,437 @@ where
         opd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_records_from_bin() {
+        let record = OpdRecord {
+            file: "optvol_optvol_6.000000e+02.npz".to_string(),
+            var: 1e-14,
+            segment_sum_square: vec![2e-14; 7],
+            modal_coefficients: vec![1e-9; 7 * 5],
+            ratios: vec![1. / 7.; 7],
+            segment_var: vec![1e-14; 7],
+        };
+        let path = std::env::temp_dir().join("opd_records.bin");
+        bincode::serialize_into(fs::File::create(&path).unwrap(), &vec![record]).unwrap();
+        let records = records_from_bin(&path).unwrap();
+        assert_eq!(records[0].segment_var, vec![1e-14; 7]);
+        assert_eq!(records[0].rms(), 1e-14f64.sqrt());
+        assert_eq!(records[0].segment_rms(), vec![2e-14f64.sqrt(); 7]);
+
+        #[derive(Serialize)]
+        struct V0 {
+            file: String,
+            var: f64,
+            segment_sum_square: Vec<f64>,
+            modal_coefficients: Vec<f64>,
+            ratios: Vec<f64>,
+        }
+        let legacy: Vec<_> = (0..3)
+            .map(|i| V0 {
+                file: format!("optvol_optvol_{i}.npz"),
+                var: 1e-14,
+                segment_sum_square: vec![2e-14; 7],
+                modal_coefficients: vec![1e-9; 7 * 5],
+                ratios: vec![1. / 7.; 7],
+            })
+            .collect();
+        let path = std::env::temp_dir().join("opd_legacy_records.bin");
+        bincode::serialize_into(fs::File::create(&path).unwrap(), &legacy).unwrap();
+        let records = records_from_bin(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].file, "optvol_optvol_2.npz");
+        assert!(records.iter().all(|r| r.segment_var.is_empty()));
+    }
+
+    #[test]
+    fn mean_segment_sum_square() {
+        let records: Vec<_> = (0..4)
+            .map(|i| OpdRecord {
+                file: format!("optvol_optvol_{i}.npz"),
+                var: 1.,
+                segment_sum_square: vec![i as f64, 1., 2.],
+                modal_coefficients: vec![],
+                ratios: vec![1. / 3.; 3],
+                segment_var: vec![],
+            })
+            .collect();
+        assert_eq!(records.mean_segment_sum_square(), vec![1.5, 1., 2.]);
+        assert!(Vec::<OpdRecord>::new().mean_segment_sum_square().is_empty());
+    }
+
+    #[test]
+    fn modal_stats_n_mode() {
+        let (n_segment, n_mode) = (2, 300);
+        let records: Vec<_> = (1..=2)
+            .map(|i| OpdRecord {
+                file: format!("optvol_optvol_{i}.npz"),
+                var: 1.,
+                segment_sum_square: vec![1e4; n_segment],
+                modal_coefficients: vec![i as f64; n_segment * n_mode],
+                ratios: vec![0.5; n_segment],
+                segment_var: vec![],
+            })
+            .collect();
+        let b2 = records.mean_modal_coefs_square();
+        assert_eq!(b2, vec![2.5; n_segment * n_mode]);
+        let res = records.mean_segment_residual_sum_square();
+        assert_eq!(res.len(), n_segment * n_mode);
+        // residual after the k-th mode: mean over the records of 1e4-(k+1)i²
+        res.chunks(n_mode).for_each(|res| {
+            res.iter()
+                .enumerate()
+                .for_each(|(k, r)| assert!((r - (1e4 - 2.5 * (k + 1) as f64)).abs() < 1e-9))
+        });
+    }
+
+    #[test]
+    fn modal_stats_without_modes() {
+        let record = |modal_coefficients: Vec<f64>| OpdRecord {
+            file: String::new(),
+            var: 1.,
+            segment_sum_square: vec![1.; 2],
+            modal_coefficients,
+            ratios: vec![0.5; 2],
+            segment_var: vec![],
+        };
+        let records = vec![record(vec![]), record(vec![])];
+        assert!(records.mean_modal_coefs_square().is_empty());
+        assert!(records.mean_segment_residual_sum_square().is_empty());
+        // the second record has less modes than the first one
+        let records = vec![record(vec![1.; 6]), record(vec![1.; 4])];
+        assert!(records.mean_modal_coefs_square().is_empty());
+        assert!(records.mean_segment_residual_sum_square().is_empty());
+    }
+
+    #[test]
+    fn percentile_var() {
+        let records: Vec<_> = [4., 1., 100., 2.]
+            .into_iter()
+            .map(|var| OpdRecord {
+                file: String::new(),
+                var,
+                segment_sum_square: vec![],
+                modal_coefficients: vec![],
+                ratios: vec![],
+                segment_var: vec![],
+            })
+            .collect();
+        assert_eq!(records.median_var(), 3.);
+        assert_eq!(records.percentile_var(0.), 1.);
+        assert_eq!(records.percentile_var(2.), 100.);
+        assert!(Vec::<OpdRecord>::new().median_var().is_nan());
+    }
+
+    #[test]
+    fn modal_covariance() {
+        let records: Vec<_> = [[1., 2.], [3., 6.], [5., 10.]]
+            .into_iter()
+            .map(|b| OpdRecord {
+                file: String::new(),
+                var: 1.,
+                segment_sum_square: vec![1.; 2],
+                modal_coefficients: [[0., 0.], b].concat(),
+                ratios: vec![0.5; 2],
+                segment_var: vec![],
+            })
+            .collect();
+        let cov = records.modal_covariance(1);
+        assert_eq!(
+            cov,
+            nalgebra::DMatrix::from_row_slice(2, 2, &[4., 8., 8., 16.])
+        );
+        assert_eq!(records.modal_covariance(0).sum(), 0.);
+        assert!(records.modal_covariance(2).is_empty());
+        // a record with less modes than the others
+        let mut records = records;
+        records[2].modal_coefficients.truncate(2);
+        assert!(records.modal_covariance(1).is_empty());
+        records[2].modal_coefficients.clear();
+        assert!(records.modal_covariance(0).is_empty());
+    }
+
+    #[test]
+    fn records_to_csv() {
+        let records = vec![OpdRecord {
+            file: "optvol \"6\".npz".to_string(),
+            var: 1.5,
+            segment_sum_square: vec![1., 2.],
+            modal_coefficients: vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+            ratios: vec![0.25, 0.75],
+            segment_var: vec![],
+        }];
+        let mut csv = Vec::new();
+        super::records_to_csv(&records, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "file,var,segment_sum_square_1,segment_sum_square_2,ratio_1,ratio_2\n\
+             \"optvol \"\"6\"\".npz\",1.5,1,2,0.25,0.75\n"
+        );
+        let mut csv = Vec::new();
+        super::records_to_csv_with_modes(&records, 2, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().ends_with("b_1_0,b_1_1,b_2_0,b_2_1"));
+        assert!(lines.next().unwrap().ends_with("0.1,0.2,0.4,0.5"));
+    }
+
+    #[test]
+    fn bootstrap_mean_var() {
+        let records: Vec<_> = (0..50)
+            .map(|i| OpdRecord {
+                file: format!("optvol_optvol_{i}.npz"),
+                var: 1. + (i % 7) as f64,
+                segment_sum_square: vec![],
+                modal_coefficients: vec![],
+                ratios: vec![],
+                segment_var: vec![],
+            })
+            .collect();
+        let (mean, lower, upper) = records.bootstrap_mean_var(1000, 42);
+        assert_eq!((mean, lower, upper), records.bootstrap_mean_var(1000, 42));
+        let mean_var = records.mean_var();
+        assert!(lower < mean_var && mean_var < upper);
+        assert!(lower < mean && mean < upper);
+    }
+
+    #[test]
+    fn case_wfe_percentiles() {
+        let summaries: Vec<_> = [50., 10., 30., 20., 40.]
+            .into_iter()
+            .enumerate()
+            .map(|(i, mean_std)| OpdSummary {
+                cfd_case: format!("case{i}"),
+                mean_std,
+                mean_segment_rss: vec![mean_std; 7],
+            })
+            .collect();
+        assert_eq!(
+            super::case_wfe_percentiles(&summaries, &[0., 50., 90., 100.]),
+            vec![10., 30., 46., 50.]
+        );
+        assert!(super::case_wfe_percentiles(&[], &[50.])[0].is_nan());
+    }
+
+    #[test]
+    fn residual_timeseries() {
+        use crate::asm::Segment;
+        let mask: Vec<_> = (0..512 * 512).map(|k| (1000..1004).contains(&k)).collect();
+        let modes = vec![2., 0., 0., 0., 0., 2., 0., 0.];
+        let mut asms = vec![ASM::S1(Segment::new(2, modes, mask))];
+        let files: Vec<_> = [[4., 2., 3., 0.], [1., 1., -1., 1.]]
+            .iter()
+            .enumerate()
+            .map(|(i, values)| {
+                let mut data = vec![f64::NAN; 512 * 512];
+                data[1000..1004].copy_from_slice(values);
+                let path = std::env::temp_dir().join(format!("residual_timeseries_{i}.npz"));
+                crate::opd::tests::write_npz(
+                    &path,
+                    &data,
+                    npyz::Order::C,
+                    &[("opd max", 4.), ("opd min", -1.)],
+                );
+                path
+            })
+            .collect();
+        for use_lsq in [false, true] {
+            let rms = super::residual_timeseries(&mut asms, &files, use_lsq).unwrap();
+            assert!((rms[0] - 1.5).abs() < 1e-12);
+            assert!((rms[1] - 0.5f64.sqrt()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn reconstruct() {
+        use crate::asm::Segment;
+        let asms: Vec<_> = [1000, 2000]
+            .into_iter()
+            .map(|offset| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + 2).contains(&k))
+                    .collect();
+                ASM::S1(Segment::new(2, vec![1., 1., 1., -1.], mask))
+            })
+            .collect();
+        assert!(matches!(
+            asms.reconstruct(&[1., 2., 3.]),
+            Err(GlaoError::NMode(4, 3))
+        ));
+        let opd = asms.reconstruct(&[1., 2., 3., -1.]).unwrap();
+        assert_eq!(opd.map()[1000..1002], [3., -1.]);
+        assert_eq!(opd.map()[2000..2002], [2., 4.]);
+        assert_eq!(opd.no_nan_opd().count(), 4);
+    }
+
+    #[test]
+    fn mirror_shape() {
+        use crate::asm::Segment;
+        let mut asms: Vec<_> = [1000, 2000, 3000]
+            .into_iter()
+            .map(|offset| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + 3).contains(&k))
+                    .collect();
+                ASM::S1(Segment::new(2, vec![1., 2., 3., 0.5, -1., 0.25], mask))
+            })
+            .collect();
+        let mut data = vec![f64::NAN; 512 * 512];
+        data[1000..1003].copy_from_slice(&[1., 2., 3.]);
+        data[2000..2003].copy_from_slice(&[-1., 0.3, 1e-3]);
+        data[3000..3003].copy_from_slice(&[7., 7., -7.]);
+        asms.least_square(&OPD::new(data)).unwrap();
+        for idx in [None, Some(vec![1])] {
+            let mut serial = vec![f64::NAN; 512 * 512];
+            for asm in &asms {
+                asm.masked_replace(&mut serial, asm.shape(idx.clone().map(|i| i.into_iter())));
+            }
+            let parallel = asms.mirror_shape(idx.map(|i| i.into_iter()));
+            assert!(parallel
+                .map()
+                .iter()
+                .zip(&serial)
+                .all(|(p, s)| p.to_bits() == s.to_bits()));
+        }
+    }
+
+    #[test]
+    fn project_out_error() {
+        use crate::asm::Segment;
+        let mask: Vec<_> = (0..512 * 512).map(|k| (1000..1004).contains(&k)).collect();
+        let asms = vec![ASM::S1(Segment::new(1, vec![1f64; 4], mask))];
+        let opd = OPD::new_with_dims(vec![1f64; 6], 2, 3).unwrap();
+        assert!(matches!(asms.project_out(&opd), Err(GlaoError::Projection)));
+        assert!(matches!(
+            asms.least_square_out(&opd),
+            Err(GlaoError::Projection)
+        ));
+        assert!(matches!(
+            asms.project_out_batch(&[&opd]),
+            Err(GlaoError::Projection)
+        ));
+    }
+
+    #[test]
+    fn edge_discontinuity() {
+        use crate::asm::Segment;
+        // pixels 511 and 512 are at both ends of consecutive rows and are not adjacent
+        let asms: Vec<_> = [(vec![511, 1000, 1001], 1.), (vec![512, 1002, 1513], 3.)]
+            .into_iter()
+            .map(|(pixels, c)| {
+                let mask: Vec<_> = (0..512 * 512).map(|k| pixels.contains(&k)).collect();
+                let mut segment = Segment::new(1, vec![1f64; 3], mask);
+                segment.coefficients = vec![c];
+                ASM::S1(segment)
+            })
+            .collect();
+        assert_eq!(asms.edge_discontinuity(), 2.);
+    }
+
+    #[test]
+    fn global_least_square() {
+        use crate::asm::Segment;
+        let segment = |pixels: &[usize]| {
+            let mask: Vec<_> = (0..512 * 512).map(|k| pixels.contains(&k)).collect();
+            let n = pixels.len();
+            let modes: Vec<_> = (0..2 * n)
+                .map(|k| if k < n { 1. } else { (k - n) as f64 })
+                .collect();
+            ASM::S1(Segment::new(2, modes, mask))
+        };
+        let mut opd = vec![f64::NAN; 512 * 512];
+        [
+            (1000, 1.5),
+            (1001, -0.5),
+            (1002, 2.),
+            (1003, 0.5),
+            (1004, 1.),
+        ]
+        .into_iter()
+        .for_each(|(k, o)| opd[k] = o);
+        let opd = OPD::new(opd);
+        // disjoint masks: same as the independent fits
+        let mut asms = vec![segment(&[1000, 1001, 1002]), segment(&[1003, 1004])];
+        asms.least_square(&opd).unwrap();
+        let b: Vec<_> = asms
+            .iter()
+            .flat_map(|asm| asm.coefficients().to_vec())
+            .collect();
+        asms.global_least_square(&opd).unwrap();
+        asms.iter()
+            .flat_map(|asm| asm.coefficients().to_vec())
+            .zip(&b)
+            .for_each(|(c, b)| assert!((c - b).abs() < 1e-12));
+        // overlapping masks: the shared pixel 1002 is fitted with the sum of both segments
+        let mut asms = vec![segment(&[1000, 1001, 1002]), segment(&[1002, 1003, 1004])];
+        asms.global_least_square(&opd).unwrap();
+        let fit = |asm: &ASM, k: usize| asm.coefficients()[0] + asm.coefficients()[1] * k as f64;
+        let model = [
+            fit(&asms[0], 0),
+            fit(&asms[0], 1),
+            fit(&asms[0], 2) + fit(&asms[1], 0),
+            fit(&asms[1], 1),
+            fit(&asms[1], 2),
+        ];
+        // 4 degrees of freedom for 5 pixels: the fit residuals are orthogonal to the modes
+        let residuals: Vec<_> = model
+            .iter()
+            .zip(&opd.map()[1000..1005])
+            .map(|(m, o)| o - m)
+            .collect();
+        assert!((residuals[0] + residuals[1] + residuals[2]).abs() < 1e-12);
+        assert!((residuals[2] + residuals[3] + residuals[4]).abs() < 1e-12);
+        assert!((residuals[1] + 2. * residuals[2]).abs() < 1e-12);
+        assert!((residuals[3] + 2. * residuals[4]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mask_area_ratios() {
+        use crate::asm::Segment;
+        let asms: Vec<_> = [(1000, 4), (2000, 12)]
+            .into_iter()
+            .map(|(offset, n)| {
+                let mask: Vec<_> = (0..512 * 512)
+                    .map(|k| (offset..offset + n).contains(&k))
+                    .collect();
+                // the modes are sampled on 4 points only
+                ASM::S1(Segment::new(1, vec![1f64; 4], mask))
+            })
+            .collect();
+        assert_eq!(asms.area_ratios(), vec![0.5, 0.5]);
+        assert_eq!(asms.mask_area_ratios(), vec![0.25, 0.75]);
+    }
+
+    #[test]
+    fn analyze_opd() {
+        let mut asms: Vec<ASM> = ASMS::from_bins().unwrap();
+        let analysis =
+            super::analyze_opd("optvol_optvol_6.000000e+02.npz", &mut asms, 500e-9).unwrap();
+        println!("OPD std: {:.0}nm", 1e9 * analysis.opd_rms);
+        println!(
+            "Segment WFE RSS: {:.0?}nm",
+            analysis
+                .segment_rms
+                .iter()
+                .map(|x| 1e9 * x)
+                .collect::<Vec<_>>()
+        );
+        println!("Residuals std: {:.0}nm", 1e9 * analysis.residual_rms);
+        assert_eq!(analysis.segment_rms.len(), 7);
+        assert_eq!(analysis.coefficients.len(), 7);
+        assert!(analysis.residual_rms < analysis.opd_rms);
+        assert!(analysis.strehl > 0. && analysis.strehl <= 1.);
+    }
+
+    #[test]
+    fn combine_segment_wfe() {
+        let rss = [10., 20., 30.];
+        let ratios = [0.5, 0.25, 0.25];
+        let wfe = super::combine_segment_wfe(&rss, &ratios);
+        assert!((wfe - (100. * 0.5 + 400. * 0.25 + 900. * 0.25f64).sqrt()).abs() < 1e-12);
+    }
+}
@@ -1,169 +1,2161 @@
-use crate::Result;
-use npyz::{npz, NpyFile};
-use std::{fs::File, io, path::Path};
+use crate::{GlaoError, Result, ASM};
+use nalgebra as na;
+use npyz::{npz, NpyFile, Order, WriterBuilder};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rustfft::{num_complex::Complex, FftDirection, FftPlanner};
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{self, Write},
+    ops::Sub,
+    path::Path,
+};
+
+/// Reads a npy array of either double or single precision floats into a double precision vector
+pub(crate) fn into_f64_vec<R: io::Read>(file: NpyFile<R>) -> Result<Vec<f64>> {
+    let mut buf = Vec::new();
+    read_f64_into(file, &mut buf)?;
+    Ok(buf)
+}
+
+/// Reads a npy array of either double or single precision floats into the double precision buffer `buf`
+///
+/// The buffer is cleared and its capacity is reused
+pub(crate) fn read_f64_into<R: io::Read>(file: NpyFile<R>, buf: &mut Vec<f64>) -> Result<()> {
+    buf.clear();
+    buf.reserve(file.len() as usize);
+    match file.try_data::<f64>() {
+        Ok(reader) => {
+            for x in reader {
+                buf.push(x?);
+            }
+        }
+        Err(file) => match file.try_data::<f32>() {
+            Ok(reader) => {
+                for x in reader {
+                    buf.push(f64::from(x?));
+                }
+            }
+            Err(file) => return Err(GlaoError::DType(file.dtype().descr())),
+        },
+    }
+    Ok(())
+}
+
+/// The arrays of a CFD dome seeing npz file other than the OPD map
+struct NpzMeta {
+    max: f64,
+    min: f64,
+    rows: usize,
+    cols: usize,
+    pixel_scale: Option<f64>,
+}
+
+/// Reads the `opd` array of a CFD dome seeing npz file into `buf`
+///
+/// A `opd` array that is not 2D is read as a 512x512 map,
+/// returns [GlaoError::Dimensions] if its size doesn't match the map
+fn read_npz_into<P: AsRef<Path>>(path: P, buf: &mut Vec<f64>) -> Result<NpzMeta> {
+    let file = io::BufReader::new(File::open(path)?);
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    let file = zip.by_name(&npz::file_name_from_array_name("opd"))?;
+    let reader = NpyFile::new(file)?;
+    let order = reader.order();
+    let shape = reader.shape().to_vec();
+    read_f64_into(reader, buf)?;
+    let (rows, cols) = match shape.as_slice() {
+        &[rows, cols] => (rows as usize, cols as usize),
+        _ => (512, 512),
+    };
+    if buf.len() != rows * cols {
+        return Err(GlaoError::Dimensions(buf.len(), rows, cols));
+    }
+    // Fortran (column-major) ordered maps are transposed into row-major order
+    if order == Order::Fortran && shape.len() == 2 {
+        let data: Vec<_> = (0..rows * cols)
+            .map(|k| buf[(k % cols) * rows + k / cols])
+            .collect();
+        buf.copy_from_slice(&data);
+    }
+
+    let file = zip.by_name(&npz::file_name_from_array_name("opd max"))?;
+    let reader = NpyFile::new(file)?;
+    let max = into_f64_vec(reader)?[0];
+
+    let file = zip.by_name(&npz::file_name_from_array_name("opd min"))?;
+    let reader = NpyFile::new(file)?;
+    let min = into_f64_vec(reader)?[0];
+
+    let pixel_scale = match zip.by_name(&npz::file_name_from_array_name("pixel scale")) {
+        Ok(file) => Some(into_f64_vec(NpyFile::new(file)?)?[0]),
+        Err(_) => None,
+    };
+
+    Ok(NpzMeta {
+        max,
+        min,
+        rows,
+        cols,
+        pixel_scale,
+    })
+}
+
+/// Writes arrays into a npz file
+///
+/// Each array is given as `(name, data, shape)`, the data being laid out according to `order`
+pub(crate) fn write_npz<P: AsRef<Path>, T: npyz::AutoSerialize + Copy>(
+    path: P,
+    arrays: &[(&str, &[T], Vec<u64>)],
+    order: Order,
+) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(File::create(path)?);
+    for (name, data, shape) in arrays {
+        zip.start_file(
+            npz::file_name_from_array_name(name),
+            zip::write::FileOptions::default(),
+        )?;
+        let mut buffer = Vec::new();
+        let mut writer = npyz::WriteOptions::new()
+            .default_dtype()
+            .shape(shape)
+            .order(order)
+            .writer(&mut buffer)
+            .begin_nd()?;
+        writer.extend(data.iter().cloned())?;
+        writer.finish()?;
+        zip.write_all(&buffer)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// 2D FFT of a `rows x cols` row-major array
+fn fft2(data: &mut [Complex<f64>], rows: usize, cols: usize, direction: FftDirection) {
+    let mut planner = FftPlanner::new();
+    planner.plan_fft(cols, direction).process(data);
+    let mut transposed: Vec<_> = (0..rows * cols)
+        .map(|k| data[(k % rows) * cols + k / rows])
+        .collect();
+    planner.plan_fft(rows, direction).process(&mut transposed);
+    data.iter_mut()
+        .enumerate()
+        .for_each(|(k, d)| *d = transposed[(k % cols) * rows + k / cols]);
+}
+
+/// Maps the frequency index of a `n` points spectrum into the zero-padded `n_pad` points spectrum
+///
+/// The Nyquist frequency of an even `n` is split evenly between the positive and negative frequencies
+fn padded_frequency(k: usize, n: usize, n_pad: usize) -> Vec<(usize, f64)> {
+    match k {
+        k if 2 * k == n => vec![(k, 0.5), (n_pad - k, 0.5)],
+        k if k < n.div_ceil(2) => vec![(k, 1.)],
+        k => vec![(n_pad - n + k, 1.)],
+    }
+}
+
+/// Checks for NaN with only a partial order
+fn is_nan<T: PartialOrd>(x: &T) -> bool {
+    x.partial_cmp(x).is_none()
+}
+
+/// Returns the flat indices and the weights of the pixels surrounding the sub-pixel position `(x,y)`
+/// of a `rows x cols` grid for bilinear interpolation
+///
+/// `x` is the column and `y` the row position in pixel, `(0,0)` being the center of the first pixel.
+/// The pixels with a zero weight are skipped.
+/// Returns `None` if the position is off-grid
+pub(crate) fn bilinear_weights(
+    rows: usize,
+    cols: usize,
+    x: f64,
+    y: f64,
+) -> Option<impl Iterator<Item = (usize, f64)>> {
+    if !(0f64..=(cols - 1) as f64).contains(&x) || !(0f64..=(rows - 1) as f64).contains(&y) {
+        return None;
+    }
+    let (i, j) = (y.floor() as usize, x.floor() as usize);
+    let (fy, fx) = (y - i as f64, x - j as f64);
+    let (i1, j1) = ((i + 1).min(rows - 1), (j + 1).min(cols - 1));
+    Some(
+        [
+            (i, j, (1. - fx) * (1. - fy)),
+            (i, j1, fx * (1. - fy)),
+            (i1, j, (1. - fx) * fy),
+            (i1, j1, fx * fy),
+        ]
+        .into_iter()
+        .filter(|(_, _, w)| *w > 0f64)
+        .map(move |(i, j, w)| (i * cols + j, w)),
+    )
+}
+
+/// Apodization window of [OPD::apodize]
+///
+/// The windows are functions of the normalized radius `ρ` within the pupil
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    /// `0.5(1+cos(πρ))`
+    Hann,
+    /// `0.54+0.46cos(πρ)`
+    Hamming,
+    /// Flat up to `ρ=1-alpha` and a Hann taper beyond
+    Tukey { alpha: f64 },
+}
+impl Window {
+    /// Returns the window value at the normalized radius `rho`
+    fn at(&self, rho: f64) -> f64 {
+        let rho = rho.clamp(0f64, 1f64);
+        match *self {
+            Window::Hann => 0.5 * (1. + (PI * rho).cos()),
+            Window::Hamming => 0.54 + 0.46 * (PI * rho).cos(),
+            Window::Tukey { alpha } if rho <= 1. - alpha => 1f64,
+            Window::Tukey { alpha } => 0.5 * (1. + (PI * (rho - 1. + alpha) / alpha).cos()),
+        }
+    }
+}
 
 /// Dome seeing opd map
 ///
-/// The dome seeing is sampled on a 512x512 grid
+/// The dome seeing is sampled on a 512x512 grid by default
 /// Values outside the exit pupil are set to NaN
+///
+/// The OPD is stored in double precision by default,
+/// `OPD<f32>` is the single precision alternative
 #[derive(Debug)]
-pub struct OPD {
-    data: Vec<f64>,
-    max: f64,
-    min: f64,
+pub struct OPD<T = f64> {
+    data: Vec<T>,
+    max: T,
+    min: T,
+    rows: usize,
+    cols: usize,
+    pixel_scale: Option<f64>,
 }
-impl Clone for OPD {
+impl<T: na::RealField + Copy> Clone for OPD<T> {
     fn clone(&self) -> Self {
-        OPD::new(self.data.clone())
+        Self {
+            data: self.data.clone(),
+            max: na::convert(f64::INFINITY),
+            min: na::convert(f64::NEG_INFINITY),
+            ..*self
+        }
     }
 }
-impl OPD {
+impl<T: na::RealField + Copy> OPD<T> {
     /// Creates a new OPD object
-    pub fn new(data: Vec<f64>) -> Self {
+    ///
+    /// The OPD is assumed to be sampled on a 512x512 grid,
+    /// use [OPD::with_dims] for other grids
+    pub fn new(data: Vec<T>) -> Self {
         Self {
             data,
-            max: f64::INFINITY,
-            min: f64::NEG_INFINITY,
+            max: na::convert(f64::INFINITY),
+            min: na::convert(f64::NEG_INFINITY),
+            rows: 512,
+            cols: 512,
+            pixel_scale: None,
+        }
+    }
+    /// Creates a new OPD object sampled on a `rows x cols` grid
+    ///
+    /// The OPD maximum and minimum are computed from the finite values of `data`
+    pub fn new_with_dims(data: Vec<T>, rows: usize, cols: usize) -> Result<Self> {
+        if data.len() != rows * cols {
+            return Err(GlaoError::Dimensions(data.len(), rows, cols));
+        }
+        let (min, max) = data.iter().filter(|x| x.is_finite()).fold(
+            (na::convert(f64::INFINITY), na::convert(f64::NEG_INFINITY)),
+            |(min, max): (T, T), &x| (min.min(x), max.max(x)),
+        );
+        Ok(Self {
+            data,
+            max,
+            min,
+            rows,
+            cols,
+            pixel_scale: None,
+        })
+    }
+    /// Sets the number of `rows` and `cols` of the OPD grid
+    ///
+    /// # Panics
+    /// If the OPD size is different from `rows x cols`
+    pub fn with_dims(self, rows: usize, cols: usize) -> Self {
+        assert_eq!(
+            self.data.len(),
+            rows * cols,
+            "OPD size doesn't match a {rows}x{cols} grid"
+        );
+        Self { rows, cols, ..self }
+    }
+    /// Sets the OPD minimum and maximum
+    pub(crate) fn with_min_max(self, min: T, max: T) -> Self {
+        Self { min, max, ..self }
+    }
+    /// Sets the pixel scale of the OPD grid
+    pub fn with_pixel_scale(self, pixel_scale: f64) -> Self {
+        Self {
+            pixel_scale: Some(pixel_scale),
+            ..self
+        }
+    }
+    /// Sets the pixel scale of the OPD grid, if any
+    pub(crate) fn with_optional_pixel_scale(self, pixel_scale: Option<f64>) -> Self {
+        Self {
+            pixel_scale,
+            ..self
         }
     }
     /// Reads a CFD dome seeing OPD map
+    ///
+    /// The map is read in either double or single precision and converted into `T`.
+    /// The pixel scale is read from the `pixel scale` array, if present
     pub fn from_npz<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = io::BufReader::new(File::open(path)?);
-        let mut zip = zip::ZipArchive::new(file)?;
-
-        let file = zip.by_name(&npz::file_name_from_array_name("opd"))?;
-        let reader = NpyFile::new(file)?;
-        let data = reader.into_vec::<f64>()?;
-
-        let file = zip.by_name(&npz::file_name_from_array_name("opd max"))?;
-        let reader = NpyFile::new(file)?;
-        let max = reader.into_vec::<f64>()?[0];
-
-        let file = zip.by_name(&npz::file_name_from_array_name("opd min"))?;
-        let reader = NpyFile::new(file)?;
-        let min = reader.into_vec::<f64>()?[0];
-
-        Ok(Self { data, max, min })
+        let mut data = Vec::new();
+        let NpzMeta {
+            max,
+            min,
+            rows,
+            cols,
+            pixel_scale,
+        } = read_npz_into(path, &mut data)?;
+        Ok(Self {
+            data: data.into_iter().map(na::convert).collect(),
+            max: na::convert(max),
+            min: na::convert(min),
+            rows,
+            cols,
+            pixel_scale,
+        })
     }
     /// Masks the OPD outside the mask by setting the values to NaN
+    ///
+    /// # Panics
+    /// If the mask and the OPD have different sizes
     pub fn mask_with(&mut self, mask: &[bool]) -> &mut Self {
+        assert_eq!(
+            mask.len(),
+            self.data.len(),
+            "mask size doesn't match the OPD size"
+        );
+        let nan: T = na::convert(f64::NAN);
         self.data
             .iter_mut()
             .zip(mask)
-            .filter(|x| !x.0.is_nan())
+            .filter(|x| !is_nan(x.0))
             .filter(|x| !x.1)
-            .for_each(|(o, _)| *o = f64::NAN);
+            .for_each(|(o, _)| *o = nan);
         self
     }
+    /// Replaces the NaN pixels within the mask with the average of their valid 4 neighbors
+    ///
+    /// The filling is repeated until all the NaN pixels within the mask are replaced,
+    /// or until none of them has a valid neighbor. Pixels outside the mask are left unchanged
+    ///
+    /// # Panics
+    /// If the mask and the OPD have different sizes
+    pub fn fill_nan_interior(&mut self, mask: &[bool]) -> &mut Self {
+        assert_eq!(
+            mask.len(),
+            self.data.len(),
+            "mask size doesn't match the OPD size"
+        );
+        let (rows, cols) = (self.rows, self.cols);
+        loop {
+            let filled: Vec<_> = (0..self.data.len())
+                .filter(|&k| mask[k] && is_nan(&self.data[k]))
+                .filter_map(|k| {
+                    let (i, j) = (k / cols, k % cols);
+                    let neighbors: Vec<T> = [
+                        (i > 0).then(|| k - cols),
+                        (i + 1 < rows).then(|| k + cols),
+                        (j > 0).then(|| k - 1),
+                        (j + 1 < cols).then(|| k + 1),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .map(|k| self.data[k])
+                    .filter(|x| !is_nan(x))
+                    .collect();
+                    (!neighbors.is_empty()).then(|| {
+                        let n: T = na::convert(neighbors.len() as f64);
+                        (k, neighbors.into_iter().fold(T::zero(), |s, x| s + x) / n)
+                    })
+                })
+                .collect();
+            if filled.is_empty() {
+                break self;
+            }
+            filled.into_iter().for_each(|(k, x)| self.data[k] = x);
+        }
+    }
     /// Remove the OPD average from the OPD
     pub fn zero_mean(&mut self) -> &mut Self {
         let mean = self.mean();
         self.data
             .iter_mut()
-            .filter(|x| !x.is_nan())
+            .filter(|x| !is_nan(*x))
             .for_each(|o| *o -= mean);
         self
     }
+    /// Returns the pixel scale of the OPD grid, if known
+    pub fn pixel_scale(&self) -> Option<f64> {
+        self.pixel_scale
+    }
+    /// Returns the number of rows and columns of the OPD grid
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+    /// Returns the `(x,y)` coordinates of each pixel of the OPD grid
+    ///
+    /// The coordinates are centered on the grid and given in the pixel scale unit,
+    /// or in pixel if the pixel scale is unknown
+    pub fn coordinates(&self) -> (Vec<f64>, Vec<f64>) {
+        let scale = self.pixel_scale.unwrap_or(1f64);
+        let (x0, y0) = ((self.cols as f64 - 1.) * 0.5, (self.rows as f64 - 1.) * 0.5);
+        (0..self.rows * self.cols)
+            .map(|k| {
+                (
+                    ((k % self.cols) as f64 - x0) * scale,
+                    ((k / self.cols) as f64 - y0) * scale,
+                )
+            })
+            .unzip()
+    }
+    /// Returns the `(x,y)` coordinates of the pixels normalized to `[-1,1]`
+    ///
+    /// `x` is the column and `y` the row index, linearly mapped from `[0,cols-1]` and `[0,rows-1]`
+    /// onto `[-1,1]`, or 0 along a dimension of a single pixel
+    pub fn normalized_coordinates(&self) -> (Vec<f64>, Vec<f64>) {
+        let normalize = |k: usize, n: usize| {
+            if n > 1 {
+                2. * k as f64 / (n - 1) as f64 - 1.
+            } else {
+                0f64
+            }
+        };
+        (0..self.rows * self.cols)
+            .map(|k| {
+                (
+                    normalize(k % self.cols, self.cols),
+                    normalize(k / self.cols, self.rows),
+                )
+            })
+            .unzip()
+    }
+    /// Returns the OPD value at pixel (`row`,`col`)
+    pub fn pixel(&self, row: usize, col: usize) -> T {
+        self.data[row * self.cols + col]
+    }
     /// Returns a reference to the opd map
-    pub fn map(&self) -> &[f64] {
+    pub fn map(&self) -> &[T] {
         self.data.as_slice()
     }
     /// Returns a mutable reference to the opd map
-    pub fn mut_map(&mut self) -> &mut [f64] {
+    pub fn mut_map(&mut self) -> &mut [T] {
         self.data.as_mut_slice()
     }
     /// Returns the opd map x 10e`-scale`
-    pub fn map_10e(&self, scale: i32) -> Vec<f64> {
-        self.data.iter().map(|x| x * 10_f64.powi(-scale)).collect()
+    pub fn map_10e(&self, scale: i32) -> Vec<T> {
+        let s: T = na::convert(10_f64.powi(-scale));
+        self.data.iter().map(|&x| x * s).collect()
     }
     /// Returns the opd maximum
-    pub fn max(&self) -> f64 {
+    pub fn max(&self) -> T {
         self.max
     }
     /// Returns the opd minimum
-    pub fn min(&self) -> f64 {
+    pub fn min(&self) -> T {
         self.min
     }
+    /// Returns the OPD footprint: `true` where the OPD is not NaN
+    pub fn valid_mask(&self) -> Vec<bool> {
+        self.data.iter().map(|x| !is_nan(x)).collect()
+    }
     /// Return an iterator on the OPD with NaN filtered out
-    pub fn no_nan_opd(&self) -> impl Iterator<Item = &f64> {
-        self.data.iter().filter(|&x| !x.is_nan())
+    pub fn no_nan_opd(&self) -> impl Iterator<Item = &T> {
+        self.data.iter().filter(|&x| !is_nan(x))
     }
-    /// Return the OPD mean
-    pub fn mean(&self) -> f64 {
-        let opd: Vec<&f64> = self.no_nan_opd().collect();
-        opd.iter().cloned().sum::<f64>() / opd.len() as f64
+    /// Returns the difference between the largest and the smallest valid values of `opd`
+    ///
+    /// Returns NaN if there is no valid value
+    fn ptv(opd: &[T]) -> T {
+        opd.iter()
+            .filter(|x| !is_nan(*x))
+            .fold(None, |range: Option<(T, T)>, &x| match range {
+                Some((min, max)) => Some((min.min(x), max.max(x))),
+                None => Some((x, x)),
+            })
+            .map_or(na::convert(f64::NAN), |(min, max)| max - min)
     }
-    /// Return the OPD variance
-    pub fn var(&self) -> f64 {
-        let opd: Vec<&f64> = self.no_nan_opd().collect();
-        let mean = self.mean();
-        opd.iter().map(|&x| x - mean).map(|x| x * x).sum::<f64>() / opd.len() as f64
+    /// Returns the OPD peak-to-valley
+    ///
+    /// The peak-to-valley is computed from the valid values of the OPD,
+    /// not from [OPD::max] and [OPD::min]
+    pub fn peak_to_valley(&self) -> T {
+        Self::ptv(&self.data)
     }
-    /// Return the OPD variance on an area specified with a mask
-    pub fn masked_var(&self, mask: &[bool]) -> f64 {
-        let opd: Vec<&f64> = self
-            .data
+    /// Returns the OPD peak-to-valley on an area specified with a mask
+    pub fn masked_peak_to_valley(&self, mask: &[bool]) -> T {
+        Self::ptv(&self.masked(mask))
+    }
+    /// Returns the values of the OPD within the mask
+    fn masked(&self, mask: &[bool]) -> Vec<T> {
+        self.data
             .iter()
             .zip(mask)
             .filter(|(_, &m)| m)
-            .map(|(o, _)| o)
-            .collect();
-        let n = opd.len() as f64;
-        let mean = opd.iter().cloned().sum::<f64>() / n;
-        opd.iter().map(|&x| x - mean).map(|x| x * x).sum::<f64>() / n
+            .map(|(&o, _)| o)
+            .collect()
     }
-    /// Return the OPD mean sum squared on an area specified with a mask
-    pub fn masked_ss(&self, mask: &[bool]) -> f64 {
-        let opd: Vec<&f64> = self
-            .data
+    /// Returns the mean and the mean square of `opd`
+    fn moments(opd: &[T]) -> (T, T) {
+        let n: T = na::convert(opd.len() as f64);
+        let (s, ss) = opd
             .iter()
-            .zip(mask)
-            .filter(|(_, &m)| m)
-            .map(|(o, _)| o)
-            .collect();
-        let n = opd.len() as f64;
-        opd.iter().map(|&x| x * x).sum::<f64>() / n
+            .fold((T::zero(), T::zero()), |(s, ss), &x| (s + x, ss + x * x));
+        (s / n, ss / n)
+    }
+    /// Return the OPD mean
+    pub fn mean(&self) -> T {
+        let opd: Vec<T> = self.no_nan_opd().cloned().collect();
+        Self::moments(&opd).0
+    }
+    /// Returns the variance of `opd`
+    fn variance(opd: &[T]) -> T {
+        let mean = Self::moments(opd).0;
+        let centered: Vec<T> = opd.iter().map(|&x| x - mean).collect();
+        Self::moments(&centered).1
+    }
+    /// Return the OPD variance
+    pub fn var(&self) -> T {
+        let opd: Vec<T> = self.no_nan_opd().cloned().collect();
+        Self::variance(&opd)
+    }
+    /// Returns the finite values of the OPD within the mask
+    fn masked_finite(&self, mask: &[bool]) -> Vec<T> {
+        self.masked(mask)
+            .into_iter()
+            .filter(|x| x.is_finite())
+            .collect()
     }
-    /// Return the OPD root sum squared on an area specified with a mask
-    pub fn masked_rss(&self, mask: &[bool]) -> f64 {
-        self.masked_ss(mask).sqrt()
+    /// Return the OPD mean on an area specified with a mask
+    ///
+    /// Non-finite pixels within the mask are skipped
+    pub fn masked_mean(&self, mask: &[bool]) -> T {
+        Self::moments(&self.masked_finite(mask)).0
+    }
+    /// Return the OPD variance on an area specified with a mask
+    ///
+    /// Non-finite pixels within the mask are skipped
+    pub fn masked_var(&self, mask: &[bool]) -> T {
+        Self::variance(&self.masked_finite(mask))
+    }
+    /// Return the OPD sum squared on an area specified with a mask
+    ///
+    /// Non-finite pixels within the mask are skipped
+    pub fn masked_ss(&self, mask: &[bool]) -> T {
+        self.masked_finite(mask)
+            .into_iter()
+            .fold(T::zero(), |ss, x| ss + x * x)
+    }
+    /// Return the OPD root sum squared on an area specified with a mask,
+    /// normalized by the number of finite pixels within the mask
+    ///
+    /// Non-finite pixels within the mask are skipped
+    pub fn masked_rss(&self, mask: &[bool]) -> T {
+        Self::moments(&self.masked_finite(mask)).1.sqrt()
     }
     /// Return the OPD standard deviation
-    pub fn std(&self) -> f64 {
+    pub fn std(&self) -> T {
         self.var().sqrt()
     }
     /// Return the OPD standard deviation on an area specified with a mask
-    pub fn masked_std(&self, mask: &[bool]) -> f64 {
+    pub fn masked_std(&self, mask: &[bool]) -> T {
         self.masked_var(mask).sqrt()
     }
     /// Return the OPD root mean square
-    pub fn rms(&self) -> f64 {
-        let opd: Vec<&f64> = self.no_nan_opd().collect();
-        (opd.iter().map(|&x| x * x).sum::<f64>() / opd.len() as f64).sqrt()
+    pub fn rms(&self) -> T {
+        let opd: Vec<T> = self.no_nan_opd().cloned().collect();
+        Self::moments(&opd).1.sqrt()
     }
     /// Return the OPD root mean square on an area specified with a mask
-    pub fn masked_rms(&self, mask: &[bool]) -> f64 {
-        let opd: Vec<&f64> = self
+    ///
+    /// Non-finite pixels within the mask are skipped
+    pub fn masked_rms(&self, mask: &[bool]) -> T {
+        Self::moments(&self.masked_finite(mask)).1.sqrt()
+    }
+    /// Returns the fraction of valid pixels clipped to either `lo` or `hi`
+    ///
+    /// A pixel is clipped if its value is equal to one of the rails within
+    /// the machine precision relative to the largest rail magnitude
+    pub fn clipped_fraction(&self, lo: T, hi: T) -> f64 {
+        let eps = T::default_epsilon() * lo.abs().max(hi.abs());
+        let (n, n_clipped) = self.no_nan_opd().fold((0usize, 0usize), |(n, c), &x| {
+            if (x - lo).abs() <= eps || (x - hi).abs() <= eps {
+                (n + 1, c + 1)
+            } else {
+                (n + 1, c)
+            }
+        });
+        n_clipped as f64 / n as f64
+    }
+}
+impl OPD {
+    /// Reads the OPD map of a CFD dome seeing npz file into the caller-provided buffer `buf`
+    ///
+    /// The buffer is cleared and its capacity reused, so that a single buffer can be
+    /// reused across many files.
+    /// Returns the OPD maximum and minimum, see [OPD::from_npz] to read the full [OPD]
+    pub fn from_npz_into<P: AsRef<Path>>(path: P, buf: &mut Vec<f64>) -> Result<(f64, f64)> {
+        let NpzMeta { max, min, .. } = read_npz_into(path, buf)?;
+        Ok((max, min))
+    }
+    /// Writes the OPD into a npz file
+    ///
+    /// The map is saved in the `rows x cols` array `opd`, NaN included,
+    /// with the `opd max` and `opd min` scalars and, if known, the `pixel scale`,
+    /// as read by [OPD::from_npz]
+    pub fn to_npz<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let shape = vec![self.rows as u64, self.cols as u64];
+        let mut arrays = vec![
+            ("opd", self.data.as_slice(), shape),
+            ("opd max", std::slice::from_ref(&self.max), vec![1]),
+            ("opd min", std::slice::from_ref(&self.min), vec![1]),
+        ];
+        if let Some(pixel_scale) = self.pixel_scale.as_ref() {
+            arrays.push(("pixel scale", std::slice::from_ref(pixel_scale), vec![1]));
+        }
+        write_npz(path, &arrays, Order::C)
+    }
+    /// Returns the OPD residual RMS within the `asm` mask after correcting as many modes as actuators
+    ///
+    /// For each actuator count in `actuator_counts`, the number of corrected modes is the
+    /// actuator count, up to the number of modes in `asm`.
+    /// The modal coefficients are the least square coefficients of the full modal basis
+    pub fn fitting_error_vs_actuators(
+        &self,
+        asm: &ASM,
+        actuator_counts: &[usize],
+    ) -> Result<Vec<f64>> {
+        let opd: Vec<f64> = self
             .data
             .iter()
-            .zip(mask)
+            .zip(asm.mask())
             .filter(|(_, &m)| m)
-            .map(|(o, _)| o)
+            .map(|(o, _)| *o)
+            .collect();
+        let b = asm.least_square_out(&opd)?;
+        let n = asm.n_point();
+        Ok(actuator_counts
+            .iter()
+            .map(|&n_act| {
+                let residual = asm.modes().chunks(n).zip(&b).take(n_act).fold(
+                    opd.clone(),
+                    |mut r, (mode, b)| {
+                        r.iter_mut().zip(mode).for_each(|(r, m)| *r -= m * b);
+                        r
+                    },
+                );
+                let residual: Vec<_> = residual.into_iter().filter(|x| !x.is_nan()).collect();
+                (residual.iter().map(|x| x * x).sum::<f64>() / residual.len() as f64).sqrt()
+            })
+            .collect())
+    }
+    /// Returns the point spread function at the given `wavelength`
+    ///
+    /// The complex pupil `exp(i*2π/λ*opd)` is set to 0 where the OPD is NaN
+    /// and zero-padded to a `pad x pad` grid (at least the size of the OPD grid).
+    /// The PSF is the squared modulus of the pupil Fourier transform, normalized to a unit sum,
+    /// with the zero frequency at pixel `(pad/2,pad/2)`.
+    /// The `wavelength` must be given in the same unit than the OPD
+    pub fn psf(&self, wavelength: f64, pad: usize) -> Vec<f64> {
+        let n = pad.max(self.rows).max(self.cols);
+        let wavenumber = 2. * PI / wavelength;
+        let mut pupil = vec![Complex::new(0f64, 0f64); n * n];
+        self.data
+            .chunks(self.cols)
+            .zip(pupil.chunks_mut(n))
+            .for_each(|(opd, pupil)| {
+                opd.iter()
+                    .zip(pupil)
+                    .filter(|(o, _)| !o.is_nan())
+                    .for_each(|(o, p)| *p = Complex::from_polar(1., wavenumber * o));
+            });
+        fft2(&mut pupil, n, n, FftDirection::Forward);
+        let intensity: Vec<_> = pupil.iter().map(|z| z.norm_sqr()).collect();
+        let total: f64 = intensity.iter().sum();
+        (0..n * n)
+            .map(|k| {
+                let (i, j) = ((k / n + n - n / 2) % n, (k % n + n - n / 2) % n);
+                intensity[i * n + j] / total
+            })
+            .collect()
+    }
+    /// Returns the modulation transfer function at the given `wavelength`
+    ///
+    /// The MTF is the modulus of the Fourier transform of the [OPD::psf],
+    /// normalized to 1 at the zero frequency which is located at pixel `(pad/2,pad/2)`.
+    /// The MTF is sampled at `1/pad` of the pupil sampling frequency,
+    /// to avoid aliasing `pad` must be at least twice the size of the pupil
+    pub fn mtf(&self, wavelength: f64, pad: usize) -> Vec<f64> {
+        let n = pad.max(self.rows).max(self.cols);
+        let psf = self.psf(wavelength, pad);
+        let mut otf: Vec<_> = psf.into_iter().map(|x| Complex::new(x, 0f64)).collect();
+        fft2(&mut otf, n, n, FftDirection::Forward);
+        let otf0 = otf[0].norm();
+        (0..n * n)
+            .map(|k| {
+                let (i, j) = ((k / n + n - n / 2) % n, (k % n + n - n / 2) % n);
+                otf[i * n + j].norm() / otf0
+            })
+            .collect()
+    }
+    /// Returns the radially binned power spectrum of the OPD
+    ///
+    /// NaN values are set to 0 and the OPD grid is assumed to span the pupil diameter `D`
+    /// (512 pixels for the CFD OPD maps).
+    /// The `k`th element is the power within the annulus of spatial frequencies `[k-1/2,k+1/2[/D`,
+    /// from 0 up to the Nyquist frequency of the smallest grid dimension,
+    /// the last element also collects the power of the corner frequencies beyond that Nyquist frequency.
+    /// The power is normalized such as the sum over all the elements is the OPD mean square
+    /// over the whole grid
+    pub fn psd(&self) -> Vec<f64> {
+        let (rows, cols) = (self.rows, self.cols);
+        let mut spectrum: Vec<_> = self
+            .data
+            .iter()
+            .map(|&o| Complex::new(if o.is_nan() { 0f64 } else { o }, 0f64))
+            .collect();
+        fft2(&mut spectrum, rows, cols, FftDirection::Forward);
+        let n = (rows * cols) as f64;
+        let n_bins = rows.min(cols) / 2 + 1;
+        // signed frequency index
+        let freq = |k: usize, n: usize| {
+            if 2 * k > n {
+                k as f64 - n as f64
+            } else {
+                k as f64
+            }
+        };
+        spectrum
+            .iter()
+            .enumerate()
+            .fold(vec![0f64; n_bins], |mut psd, (k, z)| {
+                let f = freq(k / cols, rows).hypot(freq(k % cols, cols)).round() as usize;
+                psd[f.min(n_bins - 1)] += z.norm_sqr() / (n * n);
+                psd
+            })
+    }
+    /// Returns the radially binned power spectrum of the OPD tapered with an apodization `window`
+    ///
+    /// The OPD is apodized with [OPD::apodize] before the [OPD::psd] is computed,
+    /// reducing the spectral leakage of the pupil edges
+    pub fn psd_windowed(&self, window: Window) -> Vec<f64> {
+        self.apodize(window).psd()
+    }
+    /// Returns a copy of the OPD tapered with an apodization `window`
+    ///
+    /// The window is centered on the centroid of the valid pixels and the normalized radius
+    /// is the distance to the centroid divided by the distance of the farthest valid pixel
+    pub fn apodize(&self, window: Window) -> OPD {
+        let (x, y) = self.coordinates();
+        let (n, xc, yc) = self
+            .data
+            .iter()
+            .zip(x.iter().zip(&y))
+            .filter(|(o, _)| !o.is_nan())
+            .fold((0f64, 0f64, 0f64), |(n, xc, yc), (_, (x, y))| {
+                (n + 1., xc + x, yc + y)
+            });
+        let (xc, yc) = (xc / n, yc / n);
+        let r: Vec<_> = x
+            .into_iter()
+            .zip(y)
+            .map(|(x, y)| (x - xc).hypot(y - yc))
+            .collect();
+        let r_max = self
+            .data
+            .iter()
+            .zip(&r)
+            .filter(|(o, _)| !o.is_nan())
+            .fold(0f64, |r_max, (_, &r)| r_max.max(r));
+        let data = self
+            .data
+            .iter()
+            .zip(r)
+            .map(|(o, r)| o * window.at(r / r_max))
             .collect();
-        (opd.iter().map(|&x| x * x).sum::<f64>() / opd.len() as f64).sqrt()
+        OPD::new_with_dims(data, self.rows, self.cols)
+            .expect("apodized OPD size mismatch")
+            .with_optional_pixel_scale(self.pixel_scale)
+    }
+    /// Returns the OPD embedded at the center of the smallest power of two square grid
+    ///
+    /// The border is filled with NaN, which the FFT based methods set to 0.
+    /// The original grid is recovered with [OPD::unpad]
+    pub fn pad_to_pow2(&self) -> OPD {
+        let n = self.rows.max(self.cols).next_power_of_two();
+        let (i0, j0) = ((n - self.rows) / 2, (n - self.cols) / 2);
+        let mut data = vec![f64::NAN; n * n];
+        data.chunks_mut(n)
+            .skip(i0)
+            .zip(self.data.chunks(self.cols))
+            .for_each(|(padded, row)| padded[j0..j0 + self.cols].copy_from_slice(row));
+        OPD {
+            data,
+            rows: n,
+            cols: n,
+            ..*self
+        }
+    }
+    /// Returns the central `rows x cols` region of the OPD
+    ///
+    /// This is the inverse of [OPD::pad_to_pow2] for a `rows x cols` OPD
+    ///
+    /// # Panics
+    /// If the region is larger than the OPD grid
+    pub fn unpad(&self, rows: usize, cols: usize) -> OPD {
+        assert!(
+            rows <= self.rows && cols <= self.cols,
+            "{rows}x{cols} region larger than the {}x{} OPD grid",
+            self.rows,
+            self.cols
+        );
+        let (i0, j0) = ((self.rows - rows) / 2, (self.cols - cols) / 2);
+        let data: Vec<_> = self
+            .data
+            .chunks(self.cols)
+            .skip(i0)
+            .take(rows)
+            .flat_map(|row| row[j0..j0 + cols].to_vec())
+            .collect();
+        OPD::new_with_dims(data, rows, cols)
+            .expect("unpadded OPD size mismatch")
+            .with_optional_pixel_scale(self.pixel_scale)
+    }
+    /// Returns the OPD upsampled by `factor` with Fourier interpolation
+    ///
+    /// The OPD spectrum, with NaN set to 0, is zero-padded to a `factor` times larger grid.
+    /// The pixel `(i,j)` of the upsampled OPD is sampled at `(i/factor,j/factor)` on the original grid
+    /// and is NaN if the nearest original pixel is NaN.
+    /// The Fourier interpolation assumes a periodic OPD,
+    /// edges and pupil discontinuities ring unless the OPD is apodized
+    pub fn fft_resample(&self, factor: usize) -> OPD {
+        let (rows, cols) = (self.rows, self.cols);
+        let (new_rows, new_cols) = (rows * factor, cols * factor);
+        let mut spectrum: Vec<_> = self
+            .data
+            .iter()
+            .map(|&x| Complex::new(if x.is_nan() { 0f64 } else { x }, 0f64))
+            .collect();
+        fft2(&mut spectrum, rows, cols, FftDirection::Forward);
+        let mut padded = vec![Complex::new(0f64, 0f64); new_rows * new_cols];
+        for i in 0..rows {
+            for j in 0..cols {
+                for (ii, wi) in padded_frequency(i, rows, new_rows) {
+                    for (jj, wj) in padded_frequency(j, cols, new_cols) {
+                        padded[ii * new_cols + jj] += spectrum[i * cols + j] * wi * wj;
+                    }
+                }
+            }
+        }
+        fft2(&mut padded, new_rows, new_cols, FftDirection::Inverse);
+        let nearest = |k: usize, n: usize| ((k as f64 / factor as f64).round() as usize).min(n - 1);
+        let data: Vec<_> = padded
+            .into_iter()
+            .enumerate()
+            .map(|(k, x)| {
+                let (i, j) = (nearest(k / new_cols, rows), nearest(k % new_cols, cols));
+                if self.data[i * cols + j].is_nan() {
+                    f64::NAN
+                } else {
+                    x.re / (rows * cols) as f64
+                }
+            })
+            .collect();
+        let opd =
+            OPD::new_with_dims(data, new_rows, new_cols).expect("resampled OPD size mismatch");
+        opd.with_optional_pixel_scale(
+            self.pixel_scale
+                .map(|pixel_scale| pixel_scale / factor as f64),
+        )
+    }
+    /// Resamples the OPD to a `to=(rows,cols)` grid with bilinear interpolation
+    ///
+    /// The OPD is resampled from its own [OPD::shape].
+    /// The corner pixels of both grids are aligned and the new pixels are sampled with [OPD::sample]:
+    /// a resampled pixel is NaN if any of the original pixels it is interpolated from is NaN.
+    /// The pixel scale is rescaled if the resampling ratios along the rows and the columns agree,
+    /// otherwise it is left unset
+    ///
+    /// # Panics
+    /// If either the OPD or the `to` grid has a zero dimension
+    pub fn resample(&self, to: (usize, usize)) -> OPD {
+        let (rows, cols) = self.shape();
+        assert!(
+            rows * cols > 0 && to.0 * to.1 > 0,
+            "cannot resample a {rows}x{cols} OPD to a {}x{} grid",
+            to.0,
+            to.1
+        );
+        // the resampling ratio is undefined along an axis of a single new pixel
+        let ratio = |n_from: usize, n_to: usize| {
+            (n_to > 1).then(|| (n_from - 1) as f64 / (n_to - 1) as f64)
+        };
+        let (row_ratio, col_ratio) = (ratio(rows, to.0), ratio(cols, to.1));
+        let data: Vec<_> = (0..to.0 * to.1)
+            .map(|k| {
+                let y = ((k / to.1) as f64 * row_ratio.unwrap_or(0f64)).min((rows - 1) as f64);
+                let x = ((k % to.1) as f64 * col_ratio.unwrap_or(0f64)).min((cols - 1) as f64);
+                self.sample(x, y)
+            })
+            .collect();
+        let ratio = match (row_ratio, col_ratio) {
+            (Some(row_ratio), Some(col_ratio))
+                if (row_ratio - col_ratio).abs() <= 1e-12 * row_ratio.max(col_ratio) =>
+            {
+                Some(col_ratio)
+            }
+            (Some(ratio), None) | (None, Some(ratio)) => Some(ratio),
+            _ => None,
+        };
+        let opd = OPD::new_with_dims(data, to.0, to.1).expect("resampled OPD size mismatch");
+        opd.with_optional_pixel_scale(
+            self.pixel_scale
+                .zip(ratio)
+                .map(|(pixel_scale, ratio)| pixel_scale * ratio),
+        )
+    }
+    /// Returns the OPD at the sub-pixel position `(x,y)` with bilinear interpolation
+    ///
+    /// `x` is the column and `y` the row position in pixel, `(0,0)` being the center of the first pixel.
+    /// The OPD is NaN if the position is off-grid or if any of the surrounding pixels is NaN,
+    /// a position on a row or a column of the grid has only 2 surrounding pixels
+    pub fn sample(&self, x: f64, y: f64) -> f64 {
+        bilinear_weights(self.rows, self.cols, x, y).map_or(f64::NAN, |weights| {
+            weights.map(|(k, w)| self.data[k] * w).sum()
+        })
+    }
+    /// Returns the OPD at each of the sub-pixel positions `(x,y)` in `coords`
+    ///
+    /// See [OPD::sample]
+    pub fn sample_many(&self, coords: &[(f64, f64)]) -> Vec<f64> {
+        coords.iter().map(|&(x, y)| self.sample(x, y)).collect()
+    }
+    /// Rotates the OPD by `degrees` about the pixel `(rows/2,cols/2)`, i.e. `(256,256)` for a 512x512 map
+    ///
+    /// The rotation goes from the x axis (columns) toward the y axis (rows) and
+    /// the rotated map is resampled with [OPD::sample].
+    /// Pixels without a counterpart in the original map are NaN
+    pub fn rotate(&self, degrees: f64) -> OPD {
+        let (rows, cols) = (self.rows, self.cols);
+        let (y0, x0) = ((rows / 2) as f64, (cols / 2) as f64);
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let data: Vec<_> = (0..rows * cols)
+            .map(|k| {
+                let (dx, dy) = ((k % cols) as f64 - x0, (k / cols) as f64 - y0);
+                self.sample(x0 + cos * dx + sin * dy, y0 - sin * dx + cos * dy)
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, rows, cols).expect("rotated OPD size mismatch");
+        opd.with_optional_pixel_scale(self.pixel_scale)
+    }
+    /// Returns the Strehl ratio at the given `wavelength` from the Maréchal approximation
+    ///
+    /// The Maréchal approximation `exp(-(2π*σ/λ)²)`, with σ the OPD standard deviation,
+    /// is only valid for small aberrations.
+    /// The `wavelength` must be given in the same unit than the OPD
+    pub fn strehl(&self, wavelength: f64) -> f64 {
+        (-(2. * PI * self.std() / wavelength).powi(2)).exp()
+    }
+    /// Returns the best fit plane `[a,b,c]` of the OPD, with `a+b*x+c*y` the plane
+    ///
+    /// The plane is fitted by least squares over the valid pixels,
+    /// `(x,y)` are the [OPD::normalized_coordinates].
+    /// Returns `None` if the valid pixels do not constrain a plane, i.e. if they are collinear
+    pub fn fit_plane(&self) -> Option<[f64; 3]> {
+        let (x, y) = self.normalized_coordinates();
+        let (ata, atb) = self
+            .data
+            .iter()
+            .zip(x.into_iter().zip(y))
+            .filter(|(o, _)| !o.is_nan())
+            .fold(
+                (na::Matrix3::<f64>::zeros(), na::Vector3::<f64>::zeros()),
+                |(ata, atb), (&o, (x, y))| {
+                    let a = na::Vector3::new(1f64, x, y);
+                    (ata + a * a.transpose(), atb + a * o)
+                },
+            );
+        ata.lu().solve(&atb).map(|p| [p[0], p[1], p[2]])
+    }
+    /// Returns the OPD root mean square after removing the best fit plane
+    ///
+    /// The plane is given by [OPD::fit_plane], the OPD is left unchanged.
+    /// Returns NaN if the plane cannot be fitted
+    pub fn rms_no_tip_tilt(&self) -> f64 {
+        let Some([a, b, c]) = self.fit_plane() else {
+            return f64::NAN;
+        };
+        let (x, y) = self.normalized_coordinates();
+        let (n, ss) = self
+            .data
+            .iter()
+            .zip(x.into_iter().zip(y))
+            .filter(|(o, _)| !o.is_nan())
+            .map(|(o, (x, y))| o - a - b * x - c * y)
+            .fold((0usize, 0f64), |(n, ss), r| (n + 1, ss + r * r));
+        (ss / n as f64).sqrt()
+    }
+    /// Returns the `(x,y)` slope maps of the OPD in OPD unit per pixel
+    ///
+    /// See [OPD::gradient_scaled]
+    pub fn gradient(&self) -> (OPD, OPD) {
+        self.gradient_scaled(1f64)
+    }
+    /// Returns the `(x,y)` slope maps of the OPD in OPD unit per `pixel_size` unit
+    ///
+    /// The slopes are computed with central differences along the rows (x) and the columns (y) of the grid.
+    /// A slope is NaN if any of the 2 neighboring pixels is NaN or off-grid
+    pub fn gradient_scaled(&self, pixel_size: f64) -> (OPD, OPD) {
+        let (rows, cols) = (self.rows, self.cols);
+        let slope = |prev: Option<usize>, next: Option<usize>| match (prev, next) {
+            (Some(p), Some(n)) => (self.data[n] - self.data[p]) / (2. * pixel_size),
+            _ => f64::NAN,
+        };
+        let (sx, sy): (Vec<_>, Vec<_>) = (0..rows * cols)
+            .map(|k| {
+                let (i, j) = (k / cols, k % cols);
+                (
+                    slope((j > 0).then(|| k - 1), (j + 1 < cols).then_some(k + 1)),
+                    slope(
+                        (i > 0).then(|| k - cols),
+                        (i + 1 < rows).then_some(k + cols),
+                    ),
+                )
+            })
+            .unzip();
+        let slopes = |data: Vec<f64>| {
+            let opd = OPD::new_with_dims(data, rows, cols).expect("slope map size mismatch");
+            opd.with_optional_pixel_scale(self.pixel_scale)
+        };
+        (slopes(sx), slopes(sy))
+    }
+    /// Returns the histogram of the finite OPD values in `n_bins` bins
+    ///
+    /// The bins evenly span `range`, or `[min,max]` of the finite values if `range` is `None`;
+    /// values outside the range are ignored and the last bin includes the upper bound.
+    /// Returns the `n_bins+1` bin edges and the `n_bins` counts
+    pub fn histogram(&self, n_bins: usize, range: Option<(f64, f64)>) -> (Vec<f64>, Vec<usize>) {
+        let (lo, hi) = range.unwrap_or_else(|| {
+            self.data
+                .iter()
+                .filter(|x| x.is_finite())
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &x| {
+                    (lo.min(x), hi.max(x))
+                })
+        });
+        let width = (hi - lo) / n_bins as f64;
+        let mut counts = vec![0usize; n_bins];
+        self.data
+            .iter()
+            .filter(|x| x.is_finite() && (lo..=hi).contains(*x))
+            .for_each(|&x| {
+                let i = if width > 0f64 {
+                    ((x - lo) / width) as usize
+                } else {
+                    0
+                };
+                if let Some(count) = counts.get_mut(i.min(n_bins.saturating_sub(1))) {
+                    *count += 1;
+                }
+            });
+        (
+            (0..=n_bins).map(|i| lo + i as f64 * width).collect(),
+            counts,
+        )
+    }
+    /// Returns the OPD structure function `D(r)=<(opd(x)-opd(x+r))²>` in `n_bins` separation bins
+    ///
+    /// The bins evenly span the separations from 0 to the smallest grid dimension.
+    /// Returns the separations of the bins centers, in the pixel scale unit or in pixel if the pixel scale is unknown,
+    /// and the mean of `(opd(x)-opd(x+r))²` within each bin, NaN for a bin without any pair of pixels.
+    ///
+    /// The structure function is estimated from 10⁶ pairs of valid pixels drawn at random, with a fixed seed
+    /// so the estimate is reproducible.
+    /// As the pairs are drawn uniformly over the pupil, the number of pairs per bin follows the distribution
+    /// of the separations within the pupil: the bins of the smallest and of the largest separations
+    /// hold much fewer pairs and their estimates are noisier.
+    /// The pairs are also correlated for separations larger than the OPD correlation length,
+    /// so the estimates are not independent across bins
+    pub fn structure_function(&self, n_bins: usize) -> (Vec<f64>, Vec<f64>) {
+        let n_pair = 1_000_000;
+        let valid: Vec<usize> = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| !o.is_nan())
+            .map(|(k, _)| k)
+            .collect();
+        let r_max = self.rows.min(self.cols) as f64;
+        let width = r_max / n_bins as f64;
+        let mut sums = vec![0f64; n_bins];
+        let mut counts = vec![0usize; n_bins];
+        if !valid.is_empty() {
+            let mut rng = StdRng::seed_from_u64(0);
+            for _ in 0..n_pair {
+                let k = valid[rng.gen_range(0..valid.len())];
+                let l = valid[rng.gen_range(0..valid.len())];
+                let r = ((k / self.cols) as f64 - (l / self.cols) as f64)
+                    .hypot((k % self.cols) as f64 - (l % self.cols) as f64);
+                let i = (r / width) as usize;
+                if i < n_bins {
+                    sums[i] += (self.data[k] - self.data[l]).powi(2);
+                    counts[i] += 1;
+                }
+            }
+        }
+        let scale = self.pixel_scale.unwrap_or(1f64);
+        (
+            (0..n_bins)
+                .map(|i| (i as f64 + 0.5) * width * scale)
+                .collect(),
+            sums.into_iter()
+                .zip(counts)
+                .map(|(s, n)| if n > 0 { s / n as f64 } else { f64::NAN })
+                .collect(),
+        )
+    }
+    /// Returns the azimuthally averaged OPD in `n_bins` annuli
+    ///
+    /// The annuli are centered on pixel `(rows/2,cols/2)` and span the radius of the largest inscribed disk.
+    /// Returns the radii of the annuli centers, in the pixel scale unit or in pixel if the pixel scale is unknown,
+    /// and the mean of the valid pixels within each annulus, NaN for an annulus without valid pixels
+    pub fn radial_profile(&self, n_bins: usize) -> (Vec<f64>, Vec<f64>) {
+        let (row0, col0) = ((self.rows / 2) as f64, (self.cols / 2) as f64);
+        let r_max = (self.rows.min(self.cols) / 2) as f64;
+        let width = r_max / n_bins as f64;
+        let (sums, counts) = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| !o.is_nan())
+            .filter_map(|(k, &o)| {
+                let r = ((k / self.cols) as f64 - row0).hypot((k % self.cols) as f64 - col0);
+                let i = (r / width) as usize;
+                (i < n_bins).then_some((i, o))
+            })
+            .fold(
+                (vec![0f64; n_bins], vec![0usize; n_bins]),
+                |(mut sums, mut counts), (i, o)| {
+                    sums[i] += o;
+                    counts[i] += 1;
+                    (sums, counts)
+                },
+            );
+        let scale = self.pixel_scale.unwrap_or(1f64);
+        (
+            (0..n_bins)
+                .map(|i| (i as f64 + 0.5) * width * scale)
+                .collect(),
+            sums.into_iter()
+                .zip(counts)
+                .map(|(s, n)| if n > 0 { s / n as f64 } else { f64::NAN })
+                .collect(),
+        )
+    }
+    /// Removes piston, tip and tilt from the OPD
+    ///
+    /// The best fit plane given by [OPD::fit_plane] is subtracted from the valid pixels.
+    /// The OPD is left unchanged if the plane cannot be fitted
+    pub fn remove_ptt(&mut self) -> &mut Self {
+        let Some([a, b, c]) = self.fit_plane() else {
+            return self;
+        };
+        let (x, y) = self.normalized_coordinates();
+        self.data
+            .iter_mut()
+            .zip(x.into_iter().zip(y))
+            .filter(|(o, _)| !o.is_nan())
+            .for_each(|(o, (x, y))| *o -= a + b * x + c * y);
+        self
+    }
+    /// Returns the Strehl ratio at the given `wavelength` from the point spread function
+    ///
+    /// The Strehl ratio is the ratio of the [OPD::psf] peak to the peak of the PSF
+    /// of a flat wavefront over the same pupil
+    pub fn strehl_psf(&self, wavelength: f64, pad: usize) -> f64 {
+        let flat = OPD::new(
+            self.data
+                .iter()
+                .map(|x| if x.is_nan() { f64::NAN } else { 0f64 })
+                .collect(),
+        )
+        .with_dims(self.rows, self.cols);
+        let peak = |psf: Vec<f64>| psf.into_iter().fold(f64::NEG_INFINITY, f64::max);
+        peak(self.psf(wavelength, pad)) / peak(flat.psf(wavelength, pad))
+    }
+    /// Writes the opd map as a heatmap into a PNG file
+    ///
+    /// The color scale spans the extrema of the map
+    #[cfg(feature = "complot")]
+    pub fn write_heatmap_png<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let filename = path.as_ref().to_string_lossy().to_string();
+        let _: complot::Heatmap = (
+            (self.data.as_slice(), self.shape()),
+            Some(complot::Config::new().filename(filename)),
+        )
+            .into();
+        Ok(())
+    }
+}
+
+/// Returns the per-pixel mean of the OPD `maps`
+///
+/// The maps are accumulated one at a time, so they don't need to be all in memory.
+/// A pixel that is finite in at least one map is the average of its finite values,
+/// otherwise it is NaN.
+/// The grid and the pixel scale are those of the first map
+///
+/// # Panics
+/// If there is no map or if the maps have different sizes
+pub fn mean_map<I: IntoIterator<Item = OPD>>(maps: I) -> OPD {
+    let mut maps = maps.into_iter();
+    let first = maps.next().expect("no OPD map to average");
+    let mut counts: Vec<usize> = first.data.iter().map(|x| x.is_finite() as usize).collect();
+    let mut sum: Vec<f64> = first
+        .data
+        .iter()
+        .map(|&x| if x.is_finite() { x } else { 0f64 })
+        .collect();
+    for opd in maps {
+        assert_eq!(
+            opd.data.len(),
+            sum.len(),
+            "the OPD maps have different sizes"
+        );
+        sum.iter_mut()
+            .zip(&mut counts)
+            .zip(&opd.data)
+            .filter(|(_, x)| x.is_finite())
+            .for_each(|((s, n), x)| {
+                *s += x;
+                *n += 1;
+            });
+    }
+    let data: Vec<_> = sum
+        .into_iter()
+        .zip(counts)
+        .map(|(s, n)| if n > 0 { s / n as f64 } else { f64::NAN })
+        .collect();
+    let opd = OPD::new_with_dims(data, first.rows, first.cols).expect("OPD map size mismatch");
+    opd.with_optional_pixel_scale(first.pixel_scale)
+}
+
+/// Element-wise difference of two OPD maps
+///
+/// A NaN pixel in either map gives a NaN pixel in the difference
+///
+/// # Panics
+/// If the OPD maps have different sizes
+impl<T: na::RealField + Copy> Sub<&OPD<T>> for &OPD<T> {
+    type Output = OPD<T>;
+
+    fn sub(self, rhs: &OPD<T>) -> Self::Output {
+        assert_eq!(
+            self.data.len(),
+            rhs.data.len(),
+            "the OPD maps have different sizes"
+        );
+        OPD {
+            data: self
+                .data
+                .iter()
+                .zip(&rhs.data)
+                .map(|(&a, &b)| a - b)
+                .collect(),
+            max: na::convert(f64::INFINITY),
+            min: na::convert(f64::NEG_INFINITY),
+            rows: self.rows,
+            cols: self.cols,
+            pixel_scale: self.pixel_scale,
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
     fn opd_npz() {
-        let opd = OPD::from_npz("optvol_optvol_6.000000e+02.npz").unwrap();
+        let opd: OPD = OPD::from_npz("optvol_optvol_6.000000e+02.npz").unwrap();
         println!("{}/{}", opd.no_nan_opd().count(), 512 * 512);
         println!("min/max: {:.0}nm/{:.0}nm", 1e9 * opd.min(), 1e9 * opd.max());
         println!("mean: {:.0}nm", 1e9 * opd.mean());
         println!("std: {:.0}nm", 1e9 * opd.std());
     }
+
+    pub(crate) fn write_npz<T: npyz::AutoSerialize + Copy>(
+        path: &Path,
+        opd: &[T],
+        order: Order,
+        scalars: &[(&str, T)],
+    ) {
+        let shape = match opd.len() {
+            n if n == 512 * 512 => vec![512, 512],
+            n => vec![n as u64],
+        };
+        let arrays: Vec<_> = std::iter::once(("opd", opd, shape))
+            .chain(
+                scalars
+                    .iter()
+                    .map(|(name, value)| (*name, std::slice::from_ref(value), vec![1])),
+            )
+            .collect();
+        super::write_npz(path, &arrays, order).unwrap();
+    }
+
+    #[test]
+    fn opd_npz_f32() {
+        let data: Vec<f32> = (0..512 * 512)
+            .map(|i| {
+                if i % 5 == 0 {
+                    f32::NAN
+                } else {
+                    i as f32 * 1e-12
+                }
+            })
+            .collect();
+        let path = std::env::temp_dir().join("opd_f32.npz");
+        write_npz(
+            &path,
+            &data,
+            Order::C,
+            &[("opd max", 512. * 512. * 1e-12), ("opd min", 0f32)],
+        );
+        let opd: OPD = OPD::from_npz(&path).unwrap();
+        assert_eq!(
+            opd.no_nan_opd().count(),
+            data.iter().filter(|x| !x.is_nan()).count()
+        );
+        opd.map()
+            .iter()
+            .zip(&data)
+            .filter(|(_, d)| !d.is_nan())
+            .for_each(|(o, &d)| assert_eq!(*o, d as f64));
+        assert_eq!(opd.max(), (512f32 * 512. * 1e-12) as f64);
+
+        let path = std::env::temp_dir().join("opd_i32.npz");
+        write_npz(
+            &path,
+            &[1i32, 2, 3],
+            Order::C,
+            &[("opd max", 3), ("opd min", 1)],
+        );
+        assert!(matches!(
+            OPD::<f64>::from_npz(&path),
+            Err(GlaoError::DType(_))
+        ));
+    }
+
+    #[test]
+    fn opd_npz_fortran_order() {
+        let data: Vec<f64> = (0..512 * 512)
+            .map(|i| {
+                if i % 5 == 0 {
+                    f64::NAN
+                } else {
+                    i as f64 * 1e-12
+                }
+            })
+            .collect();
+        let path = std::env::temp_dir().join("opd_c_order.npz");
+        write_npz(&path, &data, Order::C, &[("opd max", 1.), ("opd min", 0.)]);
+        let c_opd: OPD = OPD::from_npz(&path).unwrap();
+        let transposed_data: Vec<f64> = (0..512 * 512)
+            .map(|k| data[(k % 512) * 512 + k / 512])
+            .collect();
+        let path = std::env::temp_dir().join("opd_fortran_order.npz");
+        write_npz(
+            &path,
+            &transposed_data,
+            Order::Fortran,
+            &[("opd max", 1.), ("opd min", 0.)],
+        );
+        let fortran_opd: OPD = OPD::from_npz(&path).unwrap();
+        c_opd
+            .map()
+            .iter()
+            .zip(fortran_opd.map())
+            .for_each(|(c, f)| assert!(c == f || (c.is_nan() && f.is_nan())));
+    }
+
+    #[test]
+    fn opd_npz_size_mismatch() {
+        let path = std::env::temp_dir().join("opd_size_mismatch.npz");
+        write_npz(
+            &path,
+            &[1e-9; 100],
+            Order::C,
+            &[("opd max", 1e-9), ("opd min", 1e-9)],
+        );
+        assert!(matches!(
+            OPD::<f64>::from_npz(&path),
+            Err(GlaoError::Dimensions(100, 512, 512))
+        ));
+    }
+
+    #[test]
+    fn dims() {
+        let data: Vec<_> = (0..256 * 512)
+            .map(|k| ((k / 512) * 1000 + k % 512) as f64)
+            .collect();
+        let opd = OPD::new(data).with_dims(256, 512);
+        assert_eq!(opd.shape(), (256, 512));
+        assert_eq!(opd.pixel(100, 300), 100300.);
+        assert_eq!(opd.clone().shape(), (256, 512));
+    }
+
+    #[test]
+    fn new_with_dims() {
+        assert!(OPD::new_with_dims(vec![0f64; 100], 20, 10).is_err());
+        let data: Vec<_> = (0..200)
+            .map(|k| if k % 3 == 0 { f64::NAN } else { k as f64 })
+            .collect();
+        let opd = OPD::new_with_dims(data, 20, 10).unwrap();
+        assert_eq!(opd.shape(), (20, 10));
+        assert_eq!((opd.min(), opd.max()), (1., 199.));
+    }
+
+    #[test]
+    fn valid_mask() {
+        let opd = OPD::new(vec![1., f64::NAN, -2., f64::NAN, 0.]);
+        assert_eq!(opd.valid_mask(), vec![true, false, true, false, true]);
+    }
+
+    #[test]
+    fn mask_with() {
+        let mut opd = OPD::new_with_dims(vec![1., f64::NAN, 3., 4.], 2, 2).unwrap();
+        opd.mask_with(&[true, true, false, true]);
+        assert_eq!(opd.valid_mask(), vec![true, false, false, true]);
+        assert!(opd.no_nan_opd().eq([1., 4.].iter()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn mask_with_size_mismatch() {
+        OPD::new_with_dims(vec![0f64; 4], 2, 2)
+            .unwrap()
+            .mask_with(&[true; 3]);
+    }
+
+    #[test]
+    fn peak_to_valley() {
+        let data = vec![1., f64::NAN, -3., 4., 2., f64::NAN];
+        let opd = OPD::new_with_dims(data.clone(), 2, 3)
+            .unwrap()
+            .with_min_max(-10., 10.);
+        let (min, max) = data
+            .iter()
+            .filter(|x| !x.is_nan())
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), &x| {
+                (a.min(x), b.max(x))
+            });
+        assert_eq!(opd.peak_to_valley(), max - min);
+        assert_eq!(
+            opd.masked_peak_to_valley(&[true, true, false, false, true, true]),
+            1.
+        );
+        assert!(opd
+            .masked_peak_to_valley(&[false, true, false, false, false, true])
+            .is_nan());
+    }
+
+    #[test]
+    fn fill_nan_interior() {
+        let n = 16;
+        let ramp = |k: usize| (k % n) as f64 + 2. * (k / n) as f64;
+        let mask: Vec<_> = (0..n * n).map(|k| (k % n) < 12).collect();
+        let mut data: Vec<_> = (0..n * n)
+            .map(|k| if mask[k] { ramp(k) } else { f64::NAN })
+            .collect();
+        for k in [
+            3 * n + 4,
+            8 * n + 8,
+            8 * n + 9,
+            9 * n + 8,
+            9 * n + 9,
+            n * n - 5,
+        ] {
+            data[k] = f64::NAN;
+        }
+        let mut opd = OPD::new_with_dims(data, n, n).unwrap();
+        opd.fill_nan_interior(&mask);
+        assert_eq!(opd.valid_mask(), mask);
+        opd.map()
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| mask[*k])
+            .for_each(|(k, x)| assert!((x - ramp(k)).abs() < 2.));
+        assert_eq!(opd.pixel(3, 4), ramp(3 * n + 4));
+    }
+
+    #[test]
+    fn zero_mean() {
+        let mut opd = OPD::new_with_dims(vec![1., f64::NAN, 3., 8.], 2, 2).unwrap();
+        opd.zero_mean();
+        assert!(opd.mean().abs() < f64::EPSILON);
+        assert!(opd.data[1].is_nan());
+        assert_eq!(opd.data[3], 4.);
+    }
+
+    #[test]
+    fn masked_ss() {
+        let opd = OPD::new_with_dims(vec![1., f64::NAN, 2., 3.], 2, 2).unwrap();
+        let mask = [true, true, true, false];
+        assert_eq!(opd.masked_ss(&mask), 5.);
+        assert!((opd.masked_rss(&mask).powi(2) * 2. - opd.masked_ss(&mask)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn single_precision() {
+        let data: Vec<f64> = (0..512 * 512)
+            .map(|i| {
+                if i % 5 == 0 {
+                    f64::NAN
+                } else {
+                    (i as f64 * 1e-3).sin() * 1e-6
+                }
+            })
+            .collect();
+        let opd64 = OPD::new(data.clone());
+        let opd32: OPD<f32> = OPD::new(data.iter().map(|&x| x as f32).collect());
+        let mask: Vec<bool> = opd64
+            .valid_mask()
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| m && i % 2 == 0)
+            .collect();
+        assert_eq!(opd32.no_nan_opd().count(), opd64.no_nan_opd().count());
+        for (s32, s64) in [
+            (opd32.mean(), opd64.mean()),
+            (opd32.std(), opd64.std()),
+            (opd32.rms(), opd64.rms()),
+            (opd32.masked_std(&mask), opd64.masked_std(&mask)),
+        ] {
+            assert!(
+                (s32 as f64 - s64).abs() <= 1e-4 * opd64.rms(),
+                "{s32} vs {s64}"
+            );
+        }
+    }
+
+    #[test]
+    fn pixel_scale() {
+        let opd = OPD::new(vec![0f64; 512 * 512]);
+        assert_eq!(opd.pixel_scale(), None);
+        let opd = opd.with_pixel_scale(25.5 / 512.);
+        assert_eq!(opd.clone().pixel_scale(), Some(25.5 / 512.));
+
+        let path = std::env::temp_dir().join("opd_pixel_scale.npz");
+        write_npz(
+            &path,
+            opd.map(),
+            Order::C,
+            &[
+                ("opd max", 0.),
+                ("opd min", 0.),
+                ("pixel scale", 25.5 / 512.),
+            ],
+        );
+        assert_eq!(
+            OPD::<f64>::from_npz(&path).unwrap().pixel_scale(),
+            Some(25.5 / 512.)
+        );
+    }
+
+    #[test]
+    fn coordinates() {
+        let opd = OPD::new(vec![0f64; 6])
+            .with_dims(2, 3)
+            .with_pixel_scale(0.5);
+        let (x, y) = opd.coordinates();
+        assert_eq!(x, vec![-0.5, 0., 0.5, -0.5, 0., 0.5]);
+        assert_eq!(y, vec![-0.25, -0.25, -0.25, 0.25, 0.25, 0.25]);
+    }
+
+    #[test]
+    fn psf() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - 31.5, (k / n) as f64 - 31.5);
+                if x.hypot(y) < 32. {
+                    0f64
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect();
+        let opd = OPD::new(data).with_dims(n, n);
+        let psf = opd.psf(500e-9, 256);
+        assert_eq!(psf.len(), 256 * 256);
+        assert!((psf.iter().sum::<f64>() - 1.).abs() < 1e-9);
+        let peak = psf[128 * 256 + 128];
+        assert!(psf.iter().all(|&x| x <= peak));
+        // radially decreasing core, symmetric
+        assert!(psf[128 * 256 + 129] < peak);
+        assert!((psf[128 * 256 + 130] - psf[130 * 256 + 128]).abs() < 1e-12);
+        assert!(psf[128 * 256 + 130] < psf[128 * 256 + 129]);
+    }
+
+    #[test]
+    fn fitting_error_vs_actuators() {
+        use crate::asm::Segment;
+        let n = 8;
+        let modes: Vec<_> = (0..n * n)
+            .map(|k| if k / n == k % n { 1f64 } else { 0f64 })
+            .collect();
+        let asm = ASM::S1(Segment::new(n, modes, vec![true; n]));
+        let opd = OPD::new((0..n).map(|k| (k as f64 + 1.).recip()).collect()).with_dims(2, 4);
+        let residuals = opd.fitting_error_vs_actuators(&asm, &[1, 2, 4, 8]).unwrap();
+        assert!(residuals.windows(2).all(|r| r[1] < r[0]));
+        assert!(residuals[3] < 1e-12);
+    }
+
+    #[test]
+    fn mtf() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - 31.5, (k / n) as f64 - 31.5);
+                if x.hypot(y) < 32. {
+                    0f64
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect();
+        let opd = OPD::new(data).with_dims(n, n);
+        let mtf = opd.mtf(500e-9, 256);
+        assert!((mtf[128 * 256 + 128] - 1.).abs() < 1e-12);
+        let circular_mtf = |nu: f64| 2. / PI * (nu.acos() - nu * (1. - nu * nu).sqrt());
+        for k in [16, 32] {
+            let nu = k as f64 / n as f64;
+            assert!((mtf[128 * 256 + 128 + k] - circular_mtf(nu)).abs() < 2e-2);
+            assert!((mtf[(128 + k) * 256 + 128] - circular_mtf(nu)).abs() < 2e-2);
+        }
+        assert!(mtf[128 * 256 + 128 + 70] < 1e-9);
+    }
+
+    #[test]
+    fn strehl_psf() {
+        let n = 64;
+        let wavelength = 500e-9;
+        let opd = |rms: f64| {
+            let data: Vec<_> = (0..n * n)
+                .map(|k| {
+                    let (x, y) = ((k % n) as f64 - 31.5, (k / n) as f64 - 31.5);
+                    if x.hypot(y) < 32. {
+                        rms * 2f64.sqrt() * (2. * PI * x / 16.).sin()
+                    } else {
+                        f64::NAN
+                    }
+                })
+                .collect();
+            OPD::new(data).with_dims(n, n)
+        };
+        let small = opd(wavelength / 40.);
+        assert!((small.strehl_psf(wavelength, 256) - small.strehl(wavelength)).abs() < 1e-2);
+        let large = opd(wavelength / 3.);
+        assert!((large.strehl_psf(wavelength, 256) - large.strehl(wavelength)).abs() > 1e-1);
+    }
+
+    #[test]
+    fn clipped_fraction() {
+        let (lo, hi) = (-2e-6, 3e-6);
+        let data: Vec<_> = (0..1000)
+            .map(|i| match i {
+                i if i < 100 => f64::NAN,
+                i if i < 190 => lo,
+                i if i < 280 => hi,
+                i => (i as f64 - 640.) * 1e-9,
+            })
+            .collect();
+        let opd = OPD::new(data);
+        assert!((opd.clipped_fraction(lo, hi) - 0.2).abs() < 1e-12);
+    }
+
+    #[cfg(feature = "complot")]
+    #[test]
+    fn heatmap_png() {
+        let opd = OPD::new(
+            (0..512 * 512)
+                .map(|i| {
+                    if i % 7 == 0 {
+                        f64::NAN
+                    } else {
+                        (i % 512) as f64
+                    }
+                })
+                .collect(),
+        );
+        let path = std::env::temp_dir().join("opd_heatmap.png");
+        opd.write_heatmap_png(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+    }
+
+    #[test]
+    fn rms_no_tip_tilt() {
+        let opd = OPD::new_with_dims(vec![0f64; 64 * 64], 64, 64)
+            .unwrap()
+            .with_pixel_scale(0.1);
+        let (x, y) = opd.coordinates();
+        let data: Vec<_> = x
+            .into_iter()
+            .zip(y)
+            .map(|(x, y)| {
+                if x.hypot(y) > 3. {
+                    f64::NAN
+                } else {
+                    1e-6 + 2e-7 * x - 5e-7 * y
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, 64, 64)
+            .unwrap()
+            .with_pixel_scale(0.1);
+        // the normalized coordinates are the physical coordinates divided by 3.15
+        let [a, b, c] = opd.fit_plane().unwrap();
+        assert!(
+            (a - 1e-6).abs() < 1e-15 && (b - 6.3e-7).abs() < 1e-15 && (c + 1.575e-6).abs() < 1e-15
+        );
+        assert!(opd.rms() > 1e-6);
+        assert!(opd.rms_no_tip_tilt() < 1e-15);
+        // a single valid pixel doesn't constrain a plane
+        let data: Vec<_> = (0..16)
+            .map(|k| if k == 5 { 1e-6 } else { f64::NAN })
+            .collect();
+        let mut opd = OPD::new_with_dims(data, 4, 4).unwrap();
+        assert!(opd.fit_plane().is_none());
+        assert!(opd.rms_no_tip_tilt().is_nan());
+        opd.remove_ptt();
+        assert_eq!(opd.no_nan_opd().collect::<Vec<_>>(), vec![&1e-6]);
+    }
+
+    #[test]
+    fn radial_profile() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let r = ((k / n) as f64 - 32.).hypot((k % n) as f64 - 32.);
+                if r < 8. {
+                    f64::NAN
+                } else if r < 16. {
+                    1.
+                } else {
+                    2.
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n)
+            .unwrap()
+            .with_pixel_scale(0.5);
+        let (r, profile) = opd.radial_profile(4);
+        assert_eq!(r, vec![2., 6., 10., 14.]);
+        assert!(profile[0].is_nan());
+        assert_eq!(profile[1..], [1., 2., 2.]);
+    }
+
+    #[test]
+    fn psd() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| 1e-6 * (2. * PI * 8. * (k % n) as f64 / n as f64).cos())
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n).unwrap();
+        let psd = opd.psd();
+        assert_eq!(psd.len(), 33);
+        assert!((psd[8] - 0.5e-12).abs() < 1e-24);
+        assert!((psd.iter().sum::<f64>() - opd.rms().powi(2)).abs() < 1e-24);
+        // Parseval's theorem with power at the corner frequencies
+        let (rows, cols) = (48, 64);
+        let data: Vec<_> = (0..rows * cols)
+            .map(|k| {
+                let (i, j) = ((k / cols) as f64, (k % cols) as f64);
+                1e-6 * (i * 0.37 + j * j * 0.11).sin() + if k == 100 { f64::NAN } else { 0. }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, rows, cols).unwrap();
+        let mean_square = opd.no_nan_opd().map(|x| x * x).sum::<f64>() / (rows * cols) as f64;
+        let psd = opd.psd();
+        assert_eq!(psd.len(), 25);
+        assert!((psd.iter().sum::<f64>() / mean_square - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn resample() {
+        let (rows, cols) = (32, 48);
+        let data: Vec<_> = (0..rows * cols)
+            .map(|k| {
+                if k == 5 * cols + 7 {
+                    f64::NAN
+                } else {
+                    1e-6 * (k / cols) as f64 - 3e-7 * (k % cols) as f64
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, rows, cols)
+            .unwrap()
+            .with_pixel_scale(0.1);
+        let upsampled = opd.resample((63, 95));
+        assert_eq!(upsampled.shape(), (63, 95));
+        assert_eq!(upsampled.no_nan_opd().count(), 63 * 95 - 9);
+        assert!((upsampled.pixel_scale().unwrap() - 0.05).abs() < 1e-15);
+        let round_trip = upsampled.resample((rows, cols));
+        round_trip
+            .map()
+            .iter()
+            .zip(opd.map())
+            .filter(|(_, x)| !x.is_nan())
+            .for_each(|(y, x)| assert!((y - x).abs() < 1e-18));
+        assert_eq!(opd.resample((32, 95)).pixel_scale(), None);
+        let row = opd.resample((1, 95));
+        assert!((row.pixel_scale().unwrap() - 0.05).abs() < 1e-15);
+        assert_eq!(row.map()[94], opd.pixel(0, cols - 1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn resample_zero_dimension() {
+        OPD::new_with_dims(vec![0f64; 16], 4, 4)
+            .unwrap()
+            .resample((0, 8));
+    }
+
+    #[test]
+    fn remove_ptt() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 / 31.5 - 1., (k / n) as f64 / 31.5 - 1.);
+                if x.hypot(y) > 1. {
+                    f64::NAN
+                } else {
+                    1e-6 + 3e-7 * x + 4e-7 * y + 1e-8 * (x * x - y * y)
+                }
+            })
+            .collect();
+        let mut opd = OPD::new_with_dims(data, n, n).unwrap();
+        let n_valid = opd.no_nan_opd().count();
+        opd.remove_ptt();
+        assert_eq!(opd.no_nan_opd().count(), n_valid);
+        assert!(opd.fit_plane().unwrap().iter().all(|p| p.abs() < 1e-18));
+        assert!(opd.rms() < 1e-8);
+    }
+
+    #[test]
+    fn fft_resample() {
+        let n = 16;
+        let wave = |y: f64, x: f64| {
+            let w = 2. * PI / n as f64;
+            (3. * w * x).cos() + 0.5 * (2. * w * y + 5. * w * x).sin()
+        };
+        let data: Vec<_> = (0..n * n)
+            .map(|k| wave((k / n) as f64, (k % n) as f64))
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n)
+            .unwrap()
+            .with_pixel_scale(0.1);
+        let factor = 4;
+        let resampled = opd.fft_resample(factor);
+        assert_eq!(resampled.shape(), (n * factor, n * factor));
+        assert_eq!(resampled.pixel_scale(), Some(0.1 / factor as f64));
+        resampled.map().iter().enumerate().for_each(|(k, x)| {
+            let (i, j) = (k / (n * factor), k % (n * factor));
+            let expected = wave(i as f64 / factor as f64, j as f64 / factor as f64);
+            assert!((x - expected).abs() < 1e-12);
+        });
+    }
+
+    #[test]
+    fn pad_to_pow2() {
+        let (rows, cols) = (300, 300);
+        let data: Vec<_> = (0..rows * cols)
+            .map(|k| if k % 11 == 0 { f64::NAN } else { k as f64 })
+            .collect();
+        let opd = OPD::new_with_dims(data, rows, cols).unwrap();
+        let padded = opd.pad_to_pow2();
+        assert_eq!(padded.shape(), (512, 512));
+        assert_eq!(padded.no_nan_opd().count(), opd.no_nan_opd().count());
+        let unpadded = padded.unpad(rows, cols);
+        assert_eq!(unpadded.shape(), (rows, cols));
+        assert_eq!(unpadded.valid_mask(), opd.valid_mask());
+        assert!(unpadded.no_nan_opd().eq(opd.no_nan_opd()));
+    }
+
+    #[test]
+    fn apodize() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - 31.5, (k / n) as f64 - 31.5);
+                if x.hypot(y) > 30. {
+                    f64::NAN
+                } else {
+                    (2. * PI * 6.3 * x / n as f64).cos()
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n).unwrap();
+        // power away from the tone relative to the peak power
+        let sidelobes = |opd: &OPD| {
+            let mut spectrum: Vec<_> = opd
+                .map()
+                .iter()
+                .map(|&x| Complex::new(if x.is_nan() { 0f64 } else { x }, 0f64))
+                .collect();
+            fft2(&mut spectrum, n, n, FftDirection::Forward);
+            let power: Vec<_> = spectrum.iter().map(|x| x.norm_sqr()).collect();
+            let peak = power.iter().cloned().fold(0f64, f64::max);
+            let far: f64 = power
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| {
+                    let (i, j) = (k / n, k % n);
+                    let (fi, fj) = (i.min(n - i), j.min(n - j));
+                    fi > 8 || fj > 14
+                })
+                .map(|(_, p)| p)
+                .sum();
+            far / peak
+        };
+        let hard_edge = sidelobes(&opd);
+        for window in [Window::Hann, Window::Hamming, Window::Tukey { alpha: 0.5 }] {
+            let apodized = opd.apodize(window);
+            assert_eq!(apodized.valid_mask(), opd.valid_mask());
+            assert!(sidelobes(&apodized) < hard_edge, "{window:?}");
+        }
+        // power beyond the tone relative to the tone power
+        let psd_sidelobes = |psd: Vec<f64>| psd[12..].iter().sum::<f64>() / psd[6];
+        let hard_edge = psd_sidelobes(opd.psd());
+        for window in [Window::Hann, Window::Hamming, Window::Tukey { alpha: 0.5 }] {
+            assert!(
+                psd_sidelobes(opd.psd_windowed(window)) < hard_edge,
+                "{window:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn to_npz() {
+        let data: Vec<_> = (0..200)
+            .map(|k| {
+                if k % 3 == 0 {
+                    f64::NAN
+                } else {
+                    k as f64 * 1e-9
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, 10, 20)
+            .unwrap()
+            .with_pixel_scale(0.25);
+        let path = std::env::temp_dir().join("opd_to_npz.npz");
+        opd.to_npz(&path).unwrap();
+        let npz_opd: OPD = OPD::from_npz(&path).unwrap();
+        assert_eq!(npz_opd.shape(), opd.shape());
+        assert_eq!(npz_opd.pixel_scale(), Some(0.25));
+        assert_eq!((npz_opd.min(), npz_opd.max()), (opd.min(), opd.max()));
+        assert_eq!(npz_opd.no_nan_opd().count(), opd.no_nan_opd().count());
+        assert_eq!(npz_opd.valid_mask(), opd.valid_mask());
+        assert!(npz_opd.no_nan_opd().eq(opd.no_nan_opd()));
+    }
+
+    #[test]
+    fn from_npz_into() {
+        let data: Vec<_> = (0..200).map(|k| k as f64 * 1e-9).collect();
+        let opd = OPD::new_with_dims(data, 10, 20).unwrap();
+        let path = std::env::temp_dir().join("opd_from_npz_into.npz");
+        opd.to_npz(&path).unwrap();
+        let mut buf = Vec::with_capacity(1000);
+        buf.push(1f64);
+        let (max, min) = OPD::from_npz_into(&path, &mut buf).unwrap();
+        assert_eq!((min, max), (opd.min(), opd.max()));
+        assert_eq!(buf.len(), 200);
+        assert!(buf.capacity() >= 1000);
+        assert!(buf.iter().eq(opd.no_nan_opd()));
+    }
+
+    #[test]
+    fn masked_mean() {
+        let data: Vec<_> = (0..200)
+            .map(|k| match k % 7 {
+                0 => f64::NAN,
+                3 => f64::INFINITY,
+                _ => 3.5e-7,
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, 10, 20).unwrap();
+        let mask: Vec<_> = (0..200).map(|k| k < 120).collect();
+        assert!((opd.masked_mean(&mask) - 3.5e-7).abs() < 1e-20);
+        assert!(opd.masked_var(&mask).abs() < 1e-30);
+        assert!((opd.masked_rms(&mask) - 3.5e-7).abs() < 1e-20);
+        assert!((opd.masked_rss(&mask) - 3.5e-7).abs() < 1e-20);
+        assert!((opd.masked_ss(&mask) - 85. * 3.5e-7 * 3.5e-7).abs() < 1e-24);
+    }
+
+    #[test]
+    fn sub_opd() {
+        let a = OPD::new_with_dims(vec![1., f64::NAN, 3., 4.], 2, 2).unwrap();
+        let b = OPD::new_with_dims(vec![0.5, 1., f64::NAN, -4.], 2, 2).unwrap();
+        let c = &a - &b;
+        assert_eq!(c.shape(), (2, 2));
+        assert_eq!(c.valid_mask(), vec![true, false, false, true]);
+        assert!(c.no_nan_opd().eq([0.5, 8.].iter()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn sub_opd_size_mismatch() {
+        let _ = &OPD::new(vec![0f64; 4]) - &OPD::new(vec![0f64; 3]);
+    }
+
+    #[test]
+    fn mean_map() {
+        let maps = vec![
+            OPD::new_with_dims(vec![1., f64::NAN, 3., f64::NAN], 2, 2).unwrap(),
+            OPD::new_with_dims(vec![3., 2., f64::NAN, f64::NAN], 2, 2).unwrap(),
+            OPD::new_with_dims(vec![5., 4., 6., f64::NAN], 2, 2).unwrap(),
+        ];
+        let mean = super::mean_map(maps);
+        assert_eq!(mean.shape(), (2, 2));
+        assert_eq!(mean.valid_mask(), vec![true, true, true, false]);
+        assert!(mean.no_nan_opd().eq([3., 3., 4.5].iter()));
+    }
+
+    #[test]
+    fn histogram() {
+        let data = vec![0., 1., f64::NAN, 2., 2.5, 4., f64::INFINITY];
+        let opd = OPD::new(data);
+        let (edges, counts) = opd.histogram(4, None);
+        assert_eq!(edges, vec![0., 1., 2., 3., 4.]);
+        assert_eq!(counts, vec![1, 1, 2, 1]);
+        let (edges, counts) = opd.histogram(2, Some((1., 3.)));
+        assert_eq!(edges, vec![1., 2., 3.]);
+        assert_eq!(counts, vec![1, 2]);
+    }
+
+    #[test]
+    fn gradient() {
+        let (rows, cols) = (4, 5);
+        let mut data: Vec<_> = (0..rows * cols)
+            .map(|k| 2. * (k % cols) as f64 - 3. * (k / cols) as f64)
+            .collect();
+        data[7] = f64::NAN;
+        let opd = OPD::new_with_dims(data, rows, cols).unwrap();
+        let (sx, sy) = opd.gradient();
+        assert_eq!(sx.shape(), (rows, cols));
+        // first and last columns, and the neighbors of pixel 7 along the row
+        let sx_nan = [0, 4, 5, 6, 8, 9, 10, 14, 15, 19];
+        sx.map().iter().enumerate().for_each(|(k, &s)| {
+            if sx_nan.contains(&k) {
+                assert!(s.is_nan(), "sx[{k}]={s}");
+            } else {
+                assert_eq!(s, 2.);
+            }
+        });
+        // first and last rows, and the neighbors of pixel 7 along the column
+        let sy_nan = [0, 1, 2, 3, 4, 12, 15, 16, 17, 18, 19];
+        sy.map().iter().enumerate().for_each(|(k, &s)| {
+            if sy_nan.contains(&k) {
+                assert!(s.is_nan(), "sy[{k}]={s}");
+            } else {
+                assert_eq!(s, -3.);
+            }
+        });
+        let (sx, _) = opd.gradient_scaled(0.5);
+        assert_eq!(sx.pixel(2, 1), 4.);
+    }
+
+    #[test]
+    fn sample() {
+        let (rows, cols) = (3, 4);
+        let mut data: Vec<_> = (0..rows * cols)
+            .map(|k| 2. * (k % cols) as f64 - 3. * (k / cols) as f64)
+            .collect();
+        data[11] = f64::NAN;
+        let opd = OPD::new_with_dims(data, rows, cols).unwrap();
+        assert_eq!(opd.sample(2., 1.5), -0.5);
+        let samples = opd.sample_many(&[(0., 0.), (1.5, 0.25), (3., 0.), (0., 2.), (2.5, 1.5)]);
+        assert_eq!(samples[..4], [0., 2.25, 6., -6.]);
+        assert!(samples[4].is_nan());
+        assert!(opd.sample(-0.1, 1.).is_nan());
+        assert!(opd.sample(1., 2.1).is_nan());
+    }
+
+    #[test]
+    fn rotate() {
+        let n = 64;
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - 32., (k / n) as f64 - 32.);
+                if x.hypot(y) < 24. {
+                    (0.1 * x).sin() * (0.05 * y).cos() + 0.01 * x * y
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n).unwrap();
+        let identity = opd.rotate(0.);
+        assert_eq!(identity.valid_mask(), opd.valid_mask());
+        assert!(identity.no_nan_opd().eq(opd.no_nan_opd()));
+        let twice = opd.rotate(90.).rotate(90.);
+        let half_turn = opd.rotate(180.);
+        let (n_both, max_diff) = twice
+            .map()
+            .iter()
+            .zip(half_turn.map())
+            .filter(|(a, b)| !a.is_nan() && !b.is_nan())
+            .fold((0usize, 0f64), |(n, d), (a, b)| {
+                (n + 1, d.max((a - b).abs()))
+            });
+        assert!(n_both as f64 > 0.95 * opd.no_nan_opd().count() as f64);
+        assert!(max_diff < 1e-9);
+        // a point at the edge of the disk is moved by the half turn
+        assert!(opd.pixel(32, 10).is_finite());
+        assert!((half_turn.pixel(32, 54) - opd.pixel(32, 10)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn structure_function() {
+        let n = 64;
+        // a tilt along x: D(r) = r²/2 for isotropic separations
+        let data: Vec<_> = (0..n * n)
+            .map(|k| {
+                if k % 11 == 0 {
+                    f64::NAN
+                } else {
+                    (k % n) as f64
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n)
+            .unwrap()
+            .with_pixel_scale(0.5);
+        let (r, d) = opd.structure_function(8);
+        assert_eq!(r.len(), 8);
+        assert_eq!(r[0], 2.);
+        r.iter().zip(&d).skip(1).take(4).for_each(|(r, d)| {
+            let r = r / 0.5;
+            assert!((d / (0.5 * r * r) - 1.).abs() < 0.1, "D({r})={d}");
+        });
+        let (_, d) = OPD::new_with_dims(vec![1f64; n * n], n, n)
+            .unwrap()
+            .structure_function(8);
+        assert!(d.iter().all(|d| *d == 0.));
+    }
 }
@@ -0,0 +1,219 @@
+//! Zernike polynomials
+//!
+//! The polynomials are indexed with Noll's ordering, starting at `j=1` for piston,
+//! and normalized to unit RMS over the unit disk:
+//! `Zj = sqrt(n+1) Rnm(ρ)` for `m=0`,
+//! `Zj = sqrt(2(n+1)) Rnm(ρ) cos(mθ)` for even `j` and
+//! `Zj = sqrt(2(n+1)) Rnm(ρ) sin(mθ)` for odd `j`
+
+use crate::{Result, ASM, OPD};
+use nalgebra as na;
+
+/// Returns the radial order `n` and the azimuthal frequency `m` of the `j`th Noll polynomial
+///
+/// `m` is negative for the sine polynomials
+pub fn noll_to_nm(j: usize) -> (usize, i32) {
+    assert!(j > 0, "Noll index starts at 1");
+    let (mut n, mut j1) = (0usize, j - 1);
+    while j1 > n {
+        n += 1;
+        j1 -= n;
+    }
+    let m = (n % 2 + 2 * ((j1 + (n + 1) % 2) / 2)) as i32;
+    // even indices are the cosine polynomials
+    match j % 2 {
+        0 => (n, m),
+        _ => (n, -m),
+    }
+}
+fn factorial(n: usize) -> f64 {
+    (1..=n).map(|k| k as f64).product()
+}
+/// Zernike radial polynomial
+fn radial(n: usize, m: usize, rho: f64) -> f64 {
+    (0..=(n - m) / 2)
+        .map(|k| {
+            let sign = if k % 2 == 0 { 1f64 } else { -1f64 };
+            sign * factorial(n - k)
+                / (factorial(k) * factorial((n + m) / 2 - k) * factorial((n - m) / 2 - k))
+                * rho.powi((n - 2 * k) as i32)
+        })
+        .sum()
+}
+/// Returns the value of the `j`th Noll polynomial at the polar coordinates (`rho`,`theta`)
+pub fn mode(j: usize, rho: f64, theta: f64) -> f64 {
+    let (n, m) = noll_to_nm(j);
+    let r = radial(n, m.unsigned_abs() as usize, rho);
+    match m {
+        0 => (n as f64 + 1.).sqrt() * r,
+        m if m > 0 => (2. * (n as f64 + 1.)).sqrt() * r * (m as f64 * theta).cos(),
+        m => (2. * (n as f64 + 1.)).sqrt() * r * (-m as f64 * theta).sin(),
+    }
+}
+/// Least square fit of the `n_max` first polynomials to the `(value,rho,theta)` samples
+fn fit(samples: &[(f64, f64, f64)], n_max: usize) -> Vec<f64> {
+    let a = na::DMatrix::from_fn(samples.len(), n_max, |i, j| {
+        let (_, rho, theta) = samples[i];
+        mode(j + 1, rho, theta)
+    });
+    let b = na::DVector::from_iterator(samples.len(), samples.iter().map(|(o, ..)| *o));
+    a.svd(true, true)
+        .solve(&b, f64::EPSILON)
+        .map_or_else(|_| vec![f64::NAN; n_max], |c| c.as_slice().to_vec())
+}
+/// Returns the `n_max` first Zernike coefficients of `opd` in Noll's order
+///
+/// The coefficients are the least square fit of the Zernike polynomials to the valid pixels of
+/// `opd` within a disk of radius `radius`, centered on the OPD grid.
+/// `radius` is given in the unit of the OPD pixel scale, or in pixel if the pixel scale is unknown
+pub fn coefficients(opd: &OPD, n_max: usize, radius: f64) -> Vec<f64> {
+    let (x, y) = opd.coordinates();
+    let samples: Vec<_> = opd
+        .map()
+        .iter()
+        .zip(x.into_iter().zip(y))
+        .filter(|(o, _)| !o.is_nan())
+        .map(|(&o, (x, y))| (o, x.hypot(y) / radius, y.atan2(x)))
+        .filter(|(_, rho, _)| *rho <= 1f64)
+        .collect();
+    fit(&samples, n_max)
+}
+/// Compares the residual RMS of `opd` over the `asm` segment after fitting `n_mode` modes of
+/// either the segment Karhunen-Loeve basis or the Zernike polynomials
+///
+/// The Zernike polynomials are defined on a disk of radius `radius` centered on the segment
+/// and `radius` must enclose the segment.
+/// The Karhunen-Loeve residual is given by [OPD::fitting_error_vs_actuators].
+/// Returns `(kl_residual_rms, zernike_residual_rms)`
+pub fn compare_bases(opd: &OPD, asm: &ASM, n_mode: usize, radius: f64) -> Result<(f64, f64)> {
+    let kl_residual_rms = opd.fitting_error_vs_actuators(asm, &[n_mode])?[0];
+
+    let (x, y) = opd.coordinates();
+    let points: Vec<_> = opd
+        .map()
+        .iter()
+        .zip(x.into_iter().zip(y))
+        .zip(asm.mask())
+        .filter(|((o, _), &m)| m && !o.is_nan())
+        .map(|((&o, xy), _)| (o, xy))
+        .collect();
+    let n = points.len() as f64;
+    let (xc, yc) = points.iter().fold((0f64, 0f64), |(xc, yc), (_, (x, y))| {
+        (xc + x / n, yc + y / n)
+    });
+    let samples: Vec<_> = points
+        .into_iter()
+        .map(|(o, (x, y))| {
+            let (x, y) = (x - xc, y - yc);
+            (o, x.hypot(y) / radius, y.atan2(x))
+        })
+        .collect();
+    let c = fit(&samples, n_mode);
+    let ss = samples
+        .iter()
+        .map(|(o, rho, theta)| {
+            o - c
+                .iter()
+                .enumerate()
+                .map(|(j, c)| c * mode(j + 1, *rho, *theta))
+                .sum::<f64>()
+        })
+        .map(|r| r * r)
+        .sum::<f64>();
+    Ok((kl_residual_rms, (ss / n).sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noll() {
+        let nm: Vec<_> = (1..=11).map(noll_to_nm).collect();
+        assert_eq!(
+            nm,
+            vec![
+                (0, 0),
+                (1, 1),
+                (1, -1),
+                (2, 0),
+                (2, -2),
+                (2, 2),
+                (3, -1),
+                (3, 1),
+                (3, -3),
+                (3, 3),
+                (4, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn coefficients() {
+        let n = 128;
+        let opd = OPD::new_with_dims(vec![0f64; n * n], n, n).unwrap();
+        let (x, y) = opd.coordinates();
+        let radius = 60f64;
+        let data: Vec<_> = x
+            .into_iter()
+            .zip(y)
+            .map(|(x, y)| {
+                let rho = x.hypot(y) / radius;
+                if rho > 1. {
+                    f64::NAN
+                } else {
+                    3e-7 * mode(7, rho, y.atan2(x))
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n).unwrap();
+        let c = super::coefficients(&opd, 15, radius);
+        assert!((c[6] - 3e-7).abs() < 1e-12);
+        c.iter()
+            .enumerate()
+            .filter(|(j, _)| *j != 6)
+            .for_each(|(_, c)| assert!(c.abs() < 1e-12));
+    }
+
+    #[test]
+    fn compare_bases() {
+        use crate::asm::Segment;
+        let n = 64;
+        let (xc, yc, radius) = (10.5f64, -6.5f64, 12f64);
+        let polar: Vec<_> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - 31.5 - xc, (k / n) as f64 - 31.5 - yc);
+                (x.hypot(y) / radius, y.atan2(x))
+            })
+            .collect();
+        let mask: Vec<_> = polar.iter().map(|(rho, _)| *rho <= 1.).collect();
+        let n_mode = 6;
+        let modes: Vec<_> = (1..=n_mode)
+            .flat_map(|j| {
+                polar
+                    .iter()
+                    .zip(&mask)
+                    .filter(|(_, &m)| m)
+                    .map(move |((rho, theta), _)| mode(j, *rho, *theta))
+            })
+            .collect();
+        let asm = ASM::S1(Segment::new(n_mode, modes, mask.clone()));
+        let data: Vec<_> = polar
+            .iter()
+            .zip(&mask)
+            .map(|((rho, theta), &m)| {
+                if m {
+                    1e-7 * (mode(2, *rho, *theta) + 0.5 * mode(4, *rho, *theta))
+                        + 1e-9 * mode(11, *rho, *theta)
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect();
+        let opd = OPD::new_with_dims(data, n, n).unwrap();
+        let (kl, zernike) = super::compare_bases(&opd, &asm, n_mode, radius).unwrap();
+        assert!(kl < 0.05 * opd.rms());
+        assert!(zernike < 0.05 * opd.rms());
+        assert!((kl - zernike).abs() < 0.1 * kl.max(zernike));
+    }
+}
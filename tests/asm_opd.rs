@@ -4,7 +4,7 @@ use std::time::Instant;
 
 #[test]
 fn asm_opd() {
-    let opd = OPD::from_npz("optvol_optvol_6.000000e+02.npz").unwrap();
+    let opd: OPD = OPD::from_npz("optvol_optvol_6.000000e+02.npz").unwrap();
     println!("{}/{}", opd.no_nan_opd().count(), 512 * 512);
     println!("mean: {:.0}nm", 1e9 * opd.mean());
     println!("std: {:.0}nm", 1e9 * opd.std());